@@ -1,140 +1,1620 @@
 mod parser;
 
+pub use parser::{Dialect, Line};
+
 use chumsky::Parser;
 use colored::Colorize;
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
 use std::io::{self, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+/// Errors returned by envset's library functions.
+#[derive(Debug)]
+pub enum EnvsetError {
+    /// Reading or writing a file (or stdin/stdout) failed
+    Io(io::Error),
+    /// The env content doesn't parse; `line` and `col` are 1-based
+    Parse {
+        line: usize,
+        col: usize,
+        msg: String,
+    },
+    /// A `${NAME}` reference couldn't be resolved during strict expansion
+    UndefinedVariable { name: String, key: String },
+    /// A `${NAME}` placeholder in a template has no value; `line` is 1-based
+    UndefinedPlaceholder { name: String, line: usize },
+    /// Keys that were asked for explicitly aren't in the file
+    MissingKeys(Vec<String>),
+    /// A key name that can't be written to an env file
+    InvalidKey(String),
+    /// A value that should hold an integer doesn't, or would overflow
+    NotANumber { key: String, value: String },
+    /// JSON input isn't a flat object of scalar values
+    Json(String),
+    /// Distinct keys that would become the same key once normalized, as the
+    /// normalized key and the original spellings
+    KeyCollisions(Vec<(String, Vec<String>)>),
+}
+
+impl fmt::Display for EnvsetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvsetError::Io(e) => write!(f, "{}", e),
+            EnvsetError::Parse { line, col, msg } => {
+                write!(f, "parse error at line {}, column {}: {}", line, col, msg)
+            }
+            EnvsetError::UndefinedVariable { name, key } => {
+                write!(f, "undefined variable {} referenced by {}", name, key)
+            }
+            EnvsetError::UndefinedPlaceholder { name, line } => {
+                write!(f, "undefined placeholder ${{{}}} on line {}", name, line)
+            }
+            EnvsetError::MissingKeys(keys) => write!(f, "not found: {}", keys.join(", ")),
+            EnvsetError::InvalidKey(key) => write!(
+                f,
+                "invalid key {:?}, keys must start with a letter or underscore and contain \
+                 only letters, digits and underscores",
+                key
+            ),
+            EnvsetError::NotANumber { key, value } => {
+                write!(f, "{} is not an integer: {:?}", key, value)
+            }
+            EnvsetError::Json(msg) => write!(f, "{}", msg),
+            EnvsetError::KeyCollisions(collisions) => {
+                let collisions: Vec<String> = collisions
+                    .iter()
+                    .map(|(key, originals)| format!("{} all become {}", originals.join(", "), key))
+                    .collect();
+                write!(f, "keys collide: {}", collisions.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvsetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EnvsetError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EnvsetError {
+    fn from(e: io::Error) -> Self {
+        EnvsetError::Io(e)
+    }
+}
+
+/// How values are quoted when writing a .env file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum QuoteStyle {
+    /// Quote only values that need it, using double quotes, and keep the
+    /// quotes values already have
+    #[default]
+    Minimal,
+    /// Like minimal, but never write single quotes: values single-quoted in
+    /// the file are rewritten with double quotes
+    Double,
+    /// Quote values that need it with single quotes, falling back to double
+    /// quotes for values containing a single quote
+    Single,
+    /// Wrap every value in double quotes
+    Always,
+}
+
+/// Options controlling how an env file is parsed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Which flavor of env file to accept
+    pub dialect: Dialect,
+    /// The character that starts comments
+    pub comment_char: CommentChar,
+}
+
+/// The character that starts comments in an env file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CommentChar {
+    /// `#`, as in shell scripts and most .env files
+    #[default]
+    #[value(name = "#")]
+    Hash,
+    /// `;`, as in INI files
+    #[value(name = ";")]
+    Semicolon,
+}
+
+impl CommentChar {
+    pub fn as_char(self) -> char {
+        match self {
+            CommentChar::Hash => '#',
+            CommentChar::Semicolon => ';',
+        }
+    }
+}
+
+/// Line endings used when writing a .env file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LineEnding {
+    /// Match the dominant line ending of the existing file
+    #[default]
+    Keep,
+    /// Unix style `\n`
+    Lf,
+    /// Windows style `\r\n`
+    Crlf,
+}
+
+/// Case applied to every key by `fmt --normalize-keys`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeyCase {
+    /// Leave keys as they are
+    #[default]
+    None,
+    /// Uppercase every key
+    Upper,
+    /// Lowercase every key
+    Lower,
+}
 
-pub fn read_env_vars(file_path: &str) -> Result<HashMap<String, String>, std::io::Error> {
-    let path = Path::new(file_path);
+/// What to order lines by when sorting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortBy {
+    /// Order by key
+    #[default]
+    Key,
+    /// Order by value, then by key for equal values
+    Value,
+}
+
+/// Rewrites the line endings between the records in `output` as
+/// `line_ending`, resolving `Keep` to whichever ending is most common in
+/// `original`. Newlines inside multi-line quoted values are left as they are.
+pub fn apply_line_ending(
+    output: &str,
+    original: &str,
+    line_ending: LineEnding,
+    parse_options: ParseOptions,
+) -> String {
+    let crlf = match line_ending {
+        LineEnding::Keep => {
+            let crlf_count = original.matches("\r\n").count();
+            crlf_count > original.matches('\n').count() - crlf_count
+        }
+        LineEnding::Lf => false,
+        LineEnding::Crlf => true,
+    };
+    if !crlf {
+        return output.to_string();
+    }
+
+    let push_record = |converted: &mut String, record: &str| match record.strip_suffix('\n') {
+        Some(body) => {
+            converted.push_str(body.strip_suffix('\r').unwrap_or(body));
+            converted.push_str("\r\n");
+        }
+        None => converted.push_str(record),
+    };
+    let mut converted = String::with_capacity(output.len());
+    let mut rest = output;
+    for result in iter_spanned_lines(output, false, parse_options) {
+        let Ok(spanned) = result else { break };
+        push_record(&mut converted, spanned.source);
+        rest = &rest[spanned.source.len()..];
+    }
+    // Anything that doesn't parse is converted line by line
+    for line in rest.split_inclusive('\n') {
+        push_record(&mut converted, line);
+    }
+    converted
+}
+
+/// Whether a written .env file ends with a newline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FinalNewline {
+    /// Match the existing file, ending new files with a newline
+    #[default]
+    Keep,
+    /// Always end the file with a newline
+    Always,
+    /// Never end the file with a newline
+    Never,
+}
 
-    if path.exists() {
-        let contents = fs::read_to_string(path)?;
-        Ok(parse_env_content(&contents))
+/// Adds or strips the newline at the end of `output` according to `final_newline`,
+/// resolving `Keep` against `original`.
+pub fn apply_final_newline(output: &str, original: &str, final_newline: FinalNewline) -> String {
+    let newline = match final_newline {
+        FinalNewline::Keep => original.is_empty() || original.ends_with('\n'),
+        FinalNewline::Always => true,
+        FinalNewline::Never => false,
+    };
+    if !newline {
+        output.trim_end_matches(['\r', '\n']).to_string()
+    } else if !output.is_empty() && !output.ends_with('\n') {
+        format!("{}\n", output)
     } else {
-        // Create an empty .env file if it doesn't exist
-        fs::write(path, "")?;
-        Ok(HashMap::new())
+        output.to_string()
     }
 }
 
-pub fn print_parse_tree<W: Write>(file_path: &str, writer: &mut W) {
-    match fs::read_to_string(file_path) {
-        Ok(content) => match parser::parser().parse(content) {
-            Ok(lines) => {
-                let json = serde_json::to_string_pretty(&lines).unwrap();
-                writeln!(writer, "{}", json).unwrap();
+/// The `--file` value that reads the env from stdin instead of a file on disk.
+pub const STDIN_PATH: &str = "-";
+
+/// Where the read functions get the env from: a file path (or `-` for stdin),
+/// or content that has already been read, such as a file at a git revision.
+/// Paths convert into a source, so the read functions still take a `&str`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvSource<'a> {
+    Path(&'a str),
+    Content(&'a str),
+}
+
+impl<'a> From<&'a str> for EnvSource<'a> {
+    fn from(path: &'a str) -> Self {
+        EnvSource::Path(path)
+    }
+}
+
+impl<'a> From<&'a String> for EnvSource<'a> {
+    fn from(path: &'a String) -> Self {
+        EnvSource::Path(path)
+    }
+}
+
+impl EnvSource<'_> {
+    /// Reads the env like `read_env_file_contents`, dropping a leading byte
+    /// order mark from content too.
+    pub fn read(self) -> std::io::Result<String> {
+        match self {
+            EnvSource::Path(path) => read_env_file_contents(path),
+            EnvSource::Content(content) => {
+                Ok(content.strip_prefix(BOM).unwrap_or(content).to_string())
             }
-            Err(e) => {
-                eprintln!("Error parsing .env file: {:?}", e);
+        }
+    }
+
+    /// Whether there is anything to read: content always exists, and so does
+    /// stdin.
+    fn exists(self) -> bool {
+        match self {
+            EnvSource::Path(path) => path == STDIN_PATH || Path::new(path).exists(),
+            EnvSource::Content(_) => true,
+        }
+    }
+}
+
+pub fn read_env_vars<'a>(
+    source: impl Into<EnvSource<'a>>,
+    parse_options: ParseOptions,
+) -> Result<HashMap<String, String>, EnvsetError> {
+    let source = source.into();
+    match source {
+        EnvSource::Path(file_path) if !source.exists() => {
+            // Create an empty .env file if it doesn't exist
+            fs::write(file_path, "")?;
+            Ok(HashMap::new())
+        }
+        _ => {
+            let contents = source.read()?;
+            parse_env_content(&contents, parse_options)
+        }
+    }
+}
+
+/// Reads the .env file like `read_env_vars`, but with each value exactly as
+/// written between its quotes, without decoding escapes. A missing file has no
+/// values and, unlike `read_env_vars`, is not created.
+pub fn read_raw_env_vars<'a>(
+    source: impl Into<EnvSource<'a>>,
+    parse_options: ParseOptions,
+) -> Result<HashMap<String, String>, EnvsetError> {
+    let source = source.into();
+    if !source.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = source.read()?;
+    let mut env_vars = HashMap::new();
+    for result in iter_spanned_lines(&content, false, parse_options) {
+        let spanned = result.map_err(|(_, e)| e)?;
+        if let (Line::KeyValue { key, .. }, Some(raw_value)) = (spanned.line, spanned.raw_value) {
+            env_vars.insert(key, raw_value.to_string());
+        }
+    }
+    Ok(env_vars)
+}
+
+/// Raw counterpart of `read_env_vars_with_overlays`.
+pub fn read_raw_env_vars_with_overlays<'a>(
+    source: impl Into<EnvSource<'a>>,
+    overlays: &[String],
+    parse_options: ParseOptions,
+) -> Result<HashMap<String, String>, EnvsetError> {
+    let mut env_vars = read_raw_env_vars(source, parse_options)?;
+    for overlay in overlays {
+        env_vars.extend(read_raw_env_vars(overlay, parse_options)?);
+    }
+    Ok(env_vars)
+}
+
+/// Reads the .env file with each overlay file applied on top, so a key set in a
+/// later overlay wins over earlier overlays and the base file.
+pub fn read_env_vars_with_overlays<'a>(
+    source: impl Into<EnvSource<'a>>,
+    overlays: &[String],
+    parse_options: ParseOptions,
+) -> Result<HashMap<String, String>, EnvsetError> {
+    let mut env_vars = read_env_vars(source, parse_options)?;
+    for overlay in overlays {
+        env_vars.extend(read_env_vars_ordered(overlay, parse_options)?);
+    }
+    Ok(env_vars)
+}
+
+/// Reads the .env file into key/value pairs in file order. A duplicate key keeps
+/// the position of its first occurrence and the value of its last, so lookups
+/// agree with `read_env_vars`.
+pub fn read_env_vars_ordered<'a>(
+    source: impl Into<EnvSource<'a>>,
+    parse_options: ParseOptions,
+) -> Result<Vec<(String, String)>, EnvsetError> {
+    let content = source.into().read()?;
+    let lines = parse_lines(&content, parse_options)?;
+    Ok(ordered_pairs(lines))
+}
+
+fn ordered_pairs(lines: Vec<parser::Line>) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for line in lines {
+        if let parser::Line::KeyValue { key, value, .. } = line {
+            match positions.get(&key) {
+                Some(&index) => pairs[index].1 = value,
+                None => {
+                    positions.insert(key.clone(), pairs.len());
+                    pairs.push((key, value));
+                }
             }
-        },
-        Err(e) => {
-            eprintln!("Error reading .env file: {:?}", e);
         }
     }
+    pairs
 }
 
-pub fn print_env_vars_as_json<W: Write>(file_path: &str, writer: &mut W) {
-    match read_env_vars(file_path) {
-        Ok(env_vars) => {
-            let json_output = json!(env_vars);
-            writeln!(
-                writer,
-                "{}",
-                serde_json::to_string_pretty(&json_output).unwrap()
-            )
-            .unwrap();
+/// The keys added, removed and changed going from one env file to another.
+/// Each list follows the order the keys appear in the file they come from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvDiff {
+    /// Keys only in the new file, with their values
+    pub added: Vec<(String, String)>,
+    /// Keys only in the old file, with their values
+    pub removed: Vec<(String, String)>,
+    /// Keys in both files with different values, as `(key, old, new)`
+    pub changed: Vec<(String, String, String)>,
+}
+
+impl EnvDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compares the keys of two parsed env files. Comments and blank lines are
+/// ignored, and a key set more than once counts with its last value.
+pub fn diff_envs(old: &[Line], new: &[Line]) -> EnvDiff {
+    let old = ordered_pairs(old.to_vec());
+    let new = ordered_pairs(new.to_vec());
+    let old_map: HashMap<_, _> = old.iter().cloned().collect();
+    let new_map: HashMap<_, _> = new.iter().cloned().collect();
+
+    let added = new
+        .iter()
+        .filter(|(key, _)| !old_map.contains_key(key))
+        .cloned()
+        .collect();
+    let removed = old
+        .iter()
+        .filter(|(key, _)| !new_map.contains_key(key))
+        .cloned()
+        .collect();
+    let changed = old
+        .into_iter()
+        .filter_map(|(key, old_value)| match new_map.get(&key) {
+            Some(new_value) if *new_value != old_value => {
+                let new_value = new_value.clone();
+                Some((key, old_value, new_value))
+            }
+            _ => None,
+        })
+        .collect();
+
+    EnvDiff {
+        added,
+        removed,
+        changed,
+    }
+}
+
+/// How an env file's keys compare to those declared in an example file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExampleCheck {
+    /// Keys the example declares that the env file doesn't set, in example order
+    pub missing: Vec<String>,
+    /// Keys the env file sets that the example doesn't declare, in file order
+    pub extra: Vec<String>,
+}
+
+/// Compares the keys of an env file against an example file. Values are
+/// ignored, only which side each key is on matters.
+pub fn check_against_example(example: &[Line], lines: &[Line]) -> ExampleCheck {
+    let diff = diff_envs(example, lines);
+    ExampleCheck {
+        missing: diff.removed.into_iter().map(|(key, _)| key).collect(),
+        extra: diff.added.into_iter().map(|(key, _)| key).collect(),
+    }
+}
+
+/// Prints `diff` in a stable format meant for scripts, one key per line:
+/// `+KEY` for an added key, `-KEY` for a removed one and `~KEY "old" "new"` for
+/// a changed value, with the values written as JSON strings.
+pub fn print_porcelain_diff<W: Write>(diff: &EnvDiff, writer: &mut W) -> io::Result<()> {
+    for (key, _) in &diff.added {
+        writeln!(writer, "+{}", key)?;
+    }
+    for (key, _) in &diff.removed {
+        writeln!(writer, "-{}", key)?;
+    }
+    for (key, old_value, new_value) in &diff.changed {
+        writeln!(writer, "~{} {} {}", key, json!(old_value), json!(new_value))?;
+    }
+    Ok(())
+}
+
+/// Number of distinct keys, whole-line comments and blank lines in an env file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvCounts {
+    pub keys: usize,
+    pub comments: usize,
+    pub empty_lines: usize,
+}
+
+pub fn count_env_file<'a>(
+    source: impl Into<EnvSource<'a>>,
+    parse_options: ParseOptions,
+) -> Result<EnvCounts, EnvsetError> {
+    let content = source.into().read()?;
+    let lines = parse_lines(&content, parse_options)?;
+
+    let comments = lines
+        .iter()
+        .filter(|line| matches!(line, parser::Line::Comment(_)))
+        .count();
+    let empty_lines = lines
+        .iter()
+        .filter(|line| matches!(line, parser::Line::Blank))
+        .count();
+    let keys = ordered_pairs(lines).len();
+    Ok(EnvCounts {
+        keys,
+        comments,
+        empty_lines,
+    })
+}
+
+/// Parses `content` into lines, reporting the first error with its position.
+pub fn parse_lines(
+    content: &str,
+    parse_options: ParseOptions,
+) -> Result<Vec<parser::Line>, EnvsetError> {
+    iter_lines(content, parse_options).collect()
+}
+
+/// Parses `content` lazily, yielding one line at a time so large files can be
+/// transformed without holding every parsed line in memory. Iteration stops
+/// after the first error.
+pub fn iter_lines(
+    content: &str,
+    parse_options: ParseOptions,
+) -> impl Iterator<Item = Result<Line, EnvsetError>> + '_ {
+    iter_spanned_lines(content, false, parse_options)
+        .map(|result| result.map(|spanned| spanned.line).map_err(|(_, e)| e))
+}
+
+/// Parses as much of `content` as possible, skipping each line that doesn't
+/// parse rather than failing. Returns the parsed lines along with the number of
+/// every skipped line and the error that caused it to be skipped.
+pub fn parse_lines_best_effort(
+    content: &str,
+    parse_options: ParseOptions,
+) -> (Vec<Line>, Vec<(usize, EnvsetError)>) {
+    let mut lines = Vec::new();
+    let mut skipped = Vec::new();
+    for result in iter_spanned_lines(content, true, parse_options) {
+        match result {
+            Ok(spanned) => lines.push(spanned.line),
+            Err(error) => skipped.push(error),
         }
-        Err(e) => {
-            eprintln!("Error reading .env file: {:?}", e);
+    }
+    (lines, skipped)
+}
+
+/// A parsed line along with where it came from in the source.
+struct SpannedLine<'a> {
+    line: Line,
+    /// 1-based number of the first line of text the line was parsed from
+    number: usize,
+    /// The text the line was parsed from, including its line ending
+    source: &'a str,
+    /// A key-value line's value as written, between any quotes and without
+    /// unescaping
+    raw_value: Option<&'a str>,
+}
+
+/// Like `iter_lines`, but also yields where each line came from in the source.
+/// Errors come with the number of the line being parsed; with `recover` that
+/// line is skipped and parsing carries on with the next one.
+fn iter_spanned_lines(
+    content: &str,
+    recover: bool,
+    parse_options: ParseOptions,
+) -> impl Iterator<Item = Result<SpannedLine<'_>, (usize, EnvsetError)>> + '_ {
+    let comment_char = parse_options.comment_char.as_char();
+    let parser = parser::spanned_line_parser(parse_options.dialect, comment_char)
+        .map_with_span(|line, span: std::ops::Range<usize>| (line, span.end));
+    let mut rest = content;
+    let mut lines_before = 0;
+    let mut failed = false;
+
+    std::iter::from_fn(move || {
+        if rest.is_empty() || failed {
+            return None;
         }
+        // Only hand the parser the text this line can span, so each step costs
+        // the length of the line rather than the rest of the file
+        let window = &rest[..line_extent(rest, comment_char)];
+        match parser.parse(window) {
+            Ok(((mut line, value_span), end)) => {
+                // Spans count chars, so find the byte offsets to slice at
+                let byte_offset = |offset| {
+                    rest.char_indices()
+                        .nth(offset)
+                        .map_or(rest.len(), |(i, _)| i)
+                };
+                let raw_value =
+                    value_span.map(|span| &rest[byte_offset(span.start)..byte_offset(span.end)]);
+                if let Line::KeyValue { raw_value: raw, .. } = &mut line {
+                    *raw = raw_value.map(str::to_string);
+                }
+                let (consumed, remaining) = rest.split_at(byte_offset(end));
+                let number = lines_before + 1;
+                lines_before += consumed.matches('\n').count();
+                rest = remaining;
+                Some(Ok(SpannedLine {
+                    line,
+                    number,
+                    source: consumed,
+                    raw_value,
+                }))
+            }
+            Err(errors) => {
+                let (line, col) = error_position(rest, &errors[0]);
+                let error = EnvsetError::Parse {
+                    line: lines_before + line,
+                    col,
+                    msg: error_message(&errors[0]),
+                };
+                let line_number = lines_before + 1;
+                if recover {
+                    rest = rest.find('\n').map_or("", |i| &rest[i + 1..]);
+                    lines_before += 1;
+                } else {
+                    failed = true;
+                }
+                Some(Err((line_number, error)))
+            }
+        }
+    })
+}
+
+/// Returns the byte length of the first line of `content` including its line
+/// ending, extended past newlines that fall inside quotes or follow a backslash.
+/// This may overshoot where the parser stops, which only costs a little speed,
+/// but never stops short of it.
+fn line_extent(content: &str, comment_char: char) -> usize {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = '\n';
+    let mut chars = content.char_indices();
+    while let Some((index, c)) = chars.next() {
+        if escaped {
+            escaped = false;
+        } else if let Some(open) = quote {
+            if c == open {
+                quote = None;
+            } else if c == '\\' && open == '"' {
+                escaped = true;
+            }
+        } else {
+            match c {
+                '\n' => return index + 1,
+                '"' | '\'' => quote = Some(c),
+                '\\' => escaped = true,
+                // A comment runs to the end of the line whatever it contains
+                c if c == comment_char && (previous.is_whitespace() || previous == '=') => {
+                    return chars
+                        .find(|&(_, c)| c == '\n')
+                        .map_or(content.len(), |(i, _)| i + 1);
+                }
+                _ => {}
+            }
+        }
+        previous = c;
     }
+    content.len()
 }
 
+/// Returns the 1-based line and column where `error` starts in `content`.
+fn error_position(content: &str, error: &chumsky::error::Simple<char>) -> (usize, usize) {
+    let offset = error.span().start;
+    let mut line_number = 1;
+    let mut line_start = 0;
+    for (index, c) in content.chars().enumerate().take(offset) {
+        if c == '\n' {
+            line_number += 1;
+            line_start = index + 1;
+        }
+    }
+    (line_number, offset - line_start + 1)
+}
+
+/// Describes a parse error by what was found and, when the parser knows, what
+/// could have come instead. Optional padding like spaces isn't worth listing.
+fn error_message(error: &chumsky::error::Simple<char>) -> String {
+    let found = match error.found() {
+        Some('\n') | Some('\r') => "unexpected end of line".to_string(),
+        Some(c) => format!("unexpected character {:?}", c),
+        None => "unexpected end of input".to_string(),
+    };
+
+    let mut expected: Vec<Option<char>> = error
+        .expected()
+        .filter(|token| !matches!(token, Some(' ' | '\t' | '\r')))
+        .copied()
+        .collect();
+    // Characters first, then the end of the line, then the end of the input
+    expected.sort_by_key(|token| match token {
+        Some('\n') => (1, '\n'),
+        Some(c) => (0, *c),
+        None => (2, ' '),
+    });
+    let expected: Vec<String> = expected
+        .into_iter()
+        .map(|token| match token {
+            Some('\n') => "end of line".to_string(),
+            Some(c) => format!("{:?}", c),
+            None => "end of input".to_string(),
+        })
+        .collect();
+
+    match expected.split_last() {
+        None => found,
+        Some((last, [])) => format!("{}, expected {}", found, last),
+        Some((last, rest)) => format!("{}, expected {} or {}", found, rest.join(", "), last),
+    }
+}
+
+/// Parses `content` and returns a human readable report for each parse error,
+/// pointing at the offending line and column.
+pub fn validate_env_content(content: &str, parse_options: ParseOptions) -> Result<(), Vec<String>> {
+    let comment_char = parse_options.comment_char.as_char();
+    match parser::parser(parse_options.dialect, comment_char).parse(content) {
+        Ok(_) => Ok(()),
+        Err(errors) => Err(errors
+            .iter()
+            .map(|e| format_parse_error(content, e))
+            .collect()),
+    }
+}
+
+/// Flags values that probably don't mean what their author intended: values
+/// wrapped in an extra layer of quotes, like `FOO="\"bar\""`, and unquoted
+/// values with stray quote characters in them. Each warning names the key and
+/// the line it was found on.
+pub fn find_suspicious_values(
+    content: &str,
+    parse_options: ParseOptions,
+) -> Result<Vec<String>, EnvsetError> {
+    let mut warnings = Vec::new();
+
+    for result in iter_spanned_lines(content, false, parse_options) {
+        let spanned = result.map_err(|(_, e)| e)?;
+        let parser::Line::KeyValue {
+            key,
+            value,
+            quoting,
+            ..
+        } = spanned.line
+        else {
+            continue;
+        };
+
+        let double_quoted = value.len() >= 2
+            && ['"', '\'']
+                .iter()
+                .any(|&q| value.starts_with(q) && value.ends_with(q));
+        let reason = if double_quoted {
+            "looks quoted twice, the quotes are part of the value"
+        } else if quoting == Some(parser::Quoting::Unquoted) && value.contains(['"', '\'']) {
+            "contains unescaped quotes, quote the whole value instead"
+        } else {
+            continue;
+        };
+        warnings.push(format!("line {}: {} {}", spanned.number, key, reason));
+    }
+
+    Ok(warnings)
+}
+
+/// A kind of problem reported by `lint_env_content`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LintRule {
+    /// A key set more than once, so only its last value counts
+    DuplicateKey,
+    /// Spaces or tabs after an unquoted value, which are silently dropped
+    TrailingWhitespace,
+    /// A key that isn't SCREAMING_SNAKE_CASE
+    KeyCase,
+    /// An unquoted value with characters that are clearer quoted, like spaces
+    ShouldQuote,
+    /// A key set to an empty value
+    EmptyValue,
+}
+
+impl fmt::Display for LintRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LintRule::DuplicateKey => "duplicate-key",
+            LintRule::TrailingWhitespace => "trailing-whitespace",
+            LintRule::KeyCase => "key-case",
+            LintRule::ShouldQuote => "should-quote",
+            LintRule::EmptyValue => "empty-value",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A problem found by `lint_env_content`; `line` is 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintFinding {
+    pub line: usize,
+    pub rule: LintRule,
+    pub message: String,
+}
+
+/// Checks env content that parses for common smells, returning the findings
+/// in file order.
+pub fn lint_env_content(
+    content: &str,
+    parse_options: ParseOptions,
+) -> Result<Vec<LintFinding>, EnvsetError> {
+    let mut first_lines: HashMap<String, usize> = HashMap::new();
+    let mut findings = Vec::new();
+
+    for result in iter_spanned_lines(content, false, parse_options) {
+        let spanned = result.map_err(|(_, e)| e)?;
+        let parser::Line::KeyValue {
+            key,
+            value,
+            comment,
+            quoting,
+            ..
+        } = spanned.line
+        else {
+            continue;
+        };
+        let mut report = |rule, message| {
+            findings.push(LintFinding {
+                line: spanned.number,
+                rule,
+                message,
+            })
+        };
+
+        if let Some(first) = first_lines.get(&key) {
+            report(
+                LintRule::DuplicateKey,
+                format!("{} is already set on line {}", key, first),
+            );
+        } else {
+            first_lines.insert(key.clone(), spanned.number);
+        }
+        let unquoted = quoting == Some(parser::Quoting::Unquoted);
+        if unquoted
+            && comment.is_none()
+            && spanned
+                .source
+                .trim_end_matches(['\r', '\n'])
+                .ends_with([' ', '\t'])
+        {
+            report(
+                LintRule::TrailingWhitespace,
+                format!("{} has whitespace after its value", key),
+            );
+        }
+        if !key
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        {
+            report(
+                LintRule::KeyCase,
+                format!("{} isn't SCREAMING_SNAKE_CASE", key),
+            );
+        }
+        if unquoted
+            && !value.is_empty()
+            && needs_quoting(&value, parse_options.comment_char.as_char())
+        {
+            report(
+                LintRule::ShouldQuote,
+                format!("{} has a value that should be quoted", key),
+            );
+        }
+        if value.is_empty() {
+            report(LintRule::EmptyValue, format!("{} is empty", key));
+        }
+    }
+
+    Ok(findings)
+}
+
+fn format_parse_error(content: &str, error: &chumsky::error::Simple<char>) -> String {
+    let (line_number, column) = error_position(content, error);
+    let line: String = content
+        .lines()
+        .nth(line_number - 1)
+        .unwrap_or_default()
+        .to_string();
+
+    format!(
+        "line {}, column {}: {}\n{}\n{}^",
+        line_number,
+        column,
+        error_message(error),
+        line.trim_end_matches('\r'),
+        " ".repeat(column - 1)
+    )
+}
+
+pub fn print_parse_tree<'a, W: Write>(
+    source: impl Into<EnvSource<'a>>,
+    writer: &mut W,
+    parse_options: ParseOptions,
+) -> Result<(), EnvsetError> {
+    let content = source.into().read()?;
+    let lines = parse_lines(&content, parse_options)?;
+    let json = serde_json::to_string_pretty(&lines).unwrap();
+    writeln!(writer, "{}", json)?;
+    Ok(())
+}
+
+/// Prints the env vars as a JSON object, with the keys in file order so the
+/// output is stable between runs, or sorted with `sort_keys`.
+pub fn print_env_vars_as_json<'a, W: Write>(
+    source: impl Into<EnvSource<'a>>,
+    writer: &mut W,
+    filter: &KeyFilter,
+    sort_keys: bool,
+    parse_options: ParseOptions,
+) -> Result<(), EnvsetError> {
+    let mut pairs = read_env_vars_ordered(source, parse_options)?;
+    pairs.retain(|(key, _)| filter.matches(key));
+    if sort_keys {
+        pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+    let env_vars: serde_json::Map<String, serde_json::Value> = pairs
+        .into_iter()
+        .map(|(key, value)| (key, json!(value)))
+        .collect();
+    writeln!(
+        writer,
+        "{}",
+        serde_json::to_string_pretty(&env_vars).unwrap()
+    )?;
+    Ok(())
+}
+
+/// Prints the env vars as a JSON object of `{"value": ..., "comment": ...}`
+/// objects, where `comment` is the key's trailing comment or `null`. A key set
+/// more than once uses its last line.
+pub fn print_env_vars_as_json_with_comments<'a, W: Write>(
+    source: impl Into<EnvSource<'a>>,
+    writer: &mut W,
+    filter: &KeyFilter,
+    parse_options: ParseOptions,
+) -> Result<(), EnvsetError> {
+    let content = source.into().read()?;
+    let mut entries = serde_json::Map::new();
+    for line in parse_lines(&content, parse_options)? {
+        if let parser::Line::KeyValue {
+            key,
+            value,
+            comment,
+            ..
+        } = line
+        {
+            if filter.matches(&key) {
+                let comment = comment.map(|comment| comment.trim().to_string());
+                entries.insert(key, json!({ "value": value, "comment": comment }));
+            }
+        }
+    }
+    writeln!(
+        writer,
+        "{}",
+        serde_json::to_string_pretty(&entries).unwrap()
+    )?;
+    Ok(())
+}
+
+pub fn print_env_vars_as_yaml<'a, W: Write>(
+    source: impl Into<EnvSource<'a>>,
+    writer: &mut W,
+    filter: &KeyFilter,
+    parse_options: ParseOptions,
+) -> Result<(), EnvsetError> {
+    let sorted: BTreeMap<_, _> = read_env_vars_ordered(source, parse_options)?
+        .into_iter()
+        .filter(|(key, _)| filter.matches(key))
+        .collect();
+    write!(writer, "{}", serde_yaml::to_string(&sorted).unwrap())?;
+    Ok(())
+}
+
+/// Prints the vars as a flat TOML table of strings, quoting keys that aren't
+/// valid bare TOML keys.
+pub fn print_env_vars_as_toml<'a, W: Write>(
+    source: impl Into<EnvSource<'a>>,
+    writer: &mut W,
+    filter: &KeyFilter,
+    parse_options: ParseOptions,
+) -> Result<(), EnvsetError> {
+    let sorted: BTreeMap<_, _> = read_env_vars_ordered(source, parse_options)?
+        .into_iter()
+        .filter(|(key, _)| filter.matches(key))
+        .collect();
+    write!(writer, "{}", toml::to_string(&sorted).unwrap())?;
+    Ok(())
+}
+
+pub fn print_env_vars_as_export<'a, W: Write>(
+    source: impl Into<EnvSource<'a>>,
+    writer: &mut W,
+    filter: &KeyFilter,
+    strip_prefix: Option<&PrefixStrip>,
+    parse_options: ParseOptions,
+) -> Result<(), EnvsetError> {
+    let content = source.into().read()?;
+    for line in parse_lines(&content, parse_options)? {
+        match line {
+            parser::Line::KeyValue { key, value, .. } if filter.matches(&key) => {
+                let key = match strip_prefix {
+                    Some(strip) => match strip.apply(&key) {
+                        Some(key) => key,
+                        None => continue,
+                    },
+                    None => key,
+                };
+                writeln!(writer, "export {}={}", key, shell_quote(&value))?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Prints the vars in a stable normal form meant for snapshot tests: one
+/// `KEY="value"` line per key, sorted by key, with every value double-quoted
+/// and newlines, tabs, quotes and backslashes escaped. Comments, blank lines,
+/// `export` prefixes and overridden duplicates are dropped, and lines always end
+/// in `\n`. This format won't change between releases.
+pub fn print_env_vars_canonical<'a, W: Write>(
+    source: impl Into<EnvSource<'a>>,
+    writer: &mut W,
+    filter: &KeyFilter,
+    parse_options: ParseOptions,
+) -> Result<(), EnvsetError> {
+    let content = source.into().read()?;
+    let sorted = lines_to_sorted(&parse_lines(&content, parse_options)?);
+    for (key, value) in sorted.iter().filter(|(key, _)| filter.matches(key)) {
+        writeln!(writer, "{}=\"{}\"", key, escape_canonical(value))?;
+    }
+    Ok(())
+}
+
+/// Escapes a value for `print_env_vars_canonical` so it fits on one line and
+/// parses back to the same value.
+fn escape_canonical(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quotes a value for POSIX sh by wrapping it in single quotes, which disables
+/// all expansion, and writing embedded single quotes as `'\''`.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// The byte order mark some Windows editors write at the start of UTF-8 files.
+pub const BOM: char = '\u{FEFF}';
+
+/// Reads the raw contents of the env file, or of stdin when `file_path` is `-`.
+/// A leading byte order mark is dropped so it isn't mistaken for part of a key.
 pub fn read_env_file_contents(file_path: &str) -> std::io::Result<String> {
-    fs::read_to_string(file_path)
+    let content = if file_path == STDIN_PATH {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        fs::read_to_string(file_path)?
+    };
+    Ok(match content.strip_prefix(BOM) {
+        Some(stripped) => stripped.to_string(),
+        None => content,
+    })
+}
+
+/// Whether the file at `file_path` starts with a byte order mark.
+pub fn has_bom(file_path: &str) -> bool {
+    let mut start = [0; 3];
+    file_path != STDIN_PATH
+        && fs::File::open(file_path)
+            .and_then(|mut file| file.read_exact(&mut start))
+            .is_ok()
+        && start == [0xEF, 0xBB, 0xBF]
 }
 
 pub fn add_env_vars(
     content: &str,
     env_vars: &HashMap<String, String>,
-) -> Result<Vec<parser::Line>, std::io::Error> {
-    let mut lines = parser::parser().parse(content).map_err(|e| {
-        std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            format!("Error parsing .env file: {:?}", e),
-        )
-    })?;
+) -> Result<Vec<parser::Line>, EnvsetError> {
+    let pairs: Vec<(String, String)> = env_vars
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    merge_env_vars(content, &pairs, &MergeOptions::default())
+}
 
-    // Replace the last instance of each key in place
+/// Joins each new value onto the current value of its key with `separator`.
+/// Keys that don't exist yet, or are empty, just take the new value.
+pub fn append_env_vars(
+    current: &HashMap<String, String>,
+    env_vars: Vec<(String, String)>,
+    separator: &str,
+) -> Vec<(String, String)> {
+    env_vars
+        .into_iter()
+        .map(|(key, value)| match current.get(&key) {
+            Some(existing) if !existing.is_empty() => {
+                (key, format!("{}{}{}", existing, separator, value))
+            }
+            _ => (key, value),
+        })
+        .collect()
+}
+
+/// Options controlling how `merge_env_vars` applies vars to a file.
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    /// Leave keys that already exist untouched and only add new ones
+    pub no_overwrite: bool,
+    /// Write the vars that are set with an `export ` prefix
+    pub export: bool,
+    /// Trailing comment written after each var that is set, without the `#`
+    pub comment: Option<String>,
+    /// How the content is parsed; in the cmd dialect new vars get a `set ` prefix
+    pub parse_options: ParseOptions,
+}
+
+/// Applies `env_vars` on top of `content`, replacing the last occurrence of each
+/// existing key in place and appending new keys at the end in the given order.
+pub fn merge_env_vars(
+    content: &str,
+    env_vars: &[(String, String)],
+    options: &MergeOptions,
+) -> Result<Vec<parser::Line>, EnvsetError> {
+    let mut lines = parse_lines(content, options.parse_options)?;
     for (key, value) in env_vars {
-        let mut last_index = None;
-        for (index, line) in lines.iter().enumerate().rev() {
-            if let parser::Line::KeyValue { key: line_key, .. } = line {
-                if line_key == key {
-                    last_index = Some(index);
-                    break;
-                }
+        set_line(&mut lines, key, value, options);
+    }
+    Ok(lines)
+}
+
+/// Checks that `key` can be written to an env file as is.
+pub fn validate_key(key: &str) -> Result<(), EnvsetError> {
+    match parser::key_parser().parse(key) {
+        Ok(parsed) if parsed == key => Ok(()),
+        _ => Err(EnvsetError::InvalidKey(key.to_string())),
+    }
+}
+
+/// Glob patterns, matched against the uppercased key, for keys whose values
+/// are probably secrets.
+const SECRET_KEY_PATTERNS: &[&str] = &[
+    "*SECRET*",
+    "*PASSWORD*",
+    "*PASSWD*",
+    "*TOKEN*",
+    "*PRIVATE*",
+    "*CREDENTIAL*",
+    "*_KEY",
+];
+
+/// Whether `key` looks like it holds a secret, like `API_TOKEN` or `DB_PASSWORD`.
+pub fn looks_secret(key: &str) -> bool {
+    let key = key.to_uppercase();
+    SECRET_KEY_PATTERNS
+        .iter()
+        .any(|pattern| glob_matches(pattern, &key))
+}
+
+/// Adds `by` to the integer value of `key`, which may be negative to decrement.
+/// A key that isn't set yet counts as 0, so it is added with the value `by`.
+pub fn increment_env_var(
+    content: &str,
+    key: &str,
+    by: i64,
+    parse_options: ParseOptions,
+) -> Result<Vec<parser::Line>, EnvsetError> {
+    validate_key(key)?;
+    let mut lines = parse_lines(content, parse_options)?;
+    let current = match last_key_index(&lines, key) {
+        Some(index) => match &lines[index] {
+            parser::Line::KeyValue { value, .. } => value.clone(),
+            _ => unreachable!(),
+        },
+        None => "0".to_string(),
+    };
+    let not_a_number = || EnvsetError::NotANumber {
+        key: key.to_string(),
+        value: current.clone(),
+    };
+    let updated = current
+        .trim()
+        .parse::<i64>()
+        .map_err(|_| not_a_number())?
+        .checked_add(by)
+        .ok_or_else(not_a_number)?;
+    set_line(
+        &mut lines,
+        key,
+        &updated.to_string(),
+        &MergeOptions {
+            parse_options,
+            ..Default::default()
+        },
+    );
+    Ok(lines)
+}
+
+fn last_key_index(lines: &[parser::Line], key: &str) -> Option<usize> {
+    lines.iter().rposition(
+        |line| matches!(line, parser::Line::KeyValue { key: line_key, .. } if line_key == key),
+    )
+}
+
+/// Sets `key` by replacing its last occurrence in place, or appends it at the end
+/// if it isn't present. A replaced line keeps its `export ` or `set ` prefix and
+/// trailing comment, and in the cmd dialect an appended line gets `set `.
+fn set_line(lines: &mut Vec<parser::Line>, key: &str, value: &str, options: &MergeOptions) {
+    let comment = options
+        .comment
+        .as_ref()
+        .map(|comment| format!(" {}", comment));
+    if let Some(index) = last_key_index(lines, key) {
+        if options.no_overwrite {
+            return;
+        }
+        if let parser::Line::KeyValue {
+            value: line_value,
+            export,
+            comment: line_comment,
+            ..
+        } = &lines[index]
+        {
+            if line_value == value
+                && (*export || !options.export)
+                && (comment.is_none() || *line_comment == comment)
+            {
+                // Leave unchanged values (and their quoting) untouched
+                return;
             }
         }
+        let (export, set, comment) = match &lines[index] {
+            parser::Line::KeyValue {
+                export,
+                set,
+                comment: line_comment,
+                ..
+            } => (
+                options.export || *export,
+                *set,
+                // A replaced line keeps its trailing comment unless given a new one
+                comment.or_else(|| line_comment.clone()),
+            ),
+            _ => (options.export, false, comment),
+        };
+        lines[index] = parser::Line::KeyValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            comment,
+            quoting: None,
+            export,
+            set,
+            raw_value: None,
+        };
+    } else {
+        // If the key doesn't exist, add it at the end, keeping it out of
+        // a trailing comment block
+        if matches!(lines.last(), Some(parser::Line::Comment(_))) {
+            lines.push(parser::Line::Blank);
+        }
+        lines.push(parser::Line::KeyValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            comment,
+            quoting: None,
+            export: options.export,
+            set: options.parse_options.dialect == Dialect::Cmd,
+            raw_value: None,
+        });
+    }
+}
+
+/// An env file parsed into memory, keeping comments, blank lines and quoting so
+/// it can be edited and written back with minimal changes.
+#[derive(Debug, Clone, Default)]
+pub struct Env {
+    lines: Vec<parser::Line>,
+    parse_options: ParseOptions,
+}
+
+impl Env {
+    pub fn parse(content: &str, parse_options: ParseOptions) -> Result<Self, EnvsetError> {
+        Ok(Env {
+            lines: parse_lines(content, parse_options)?,
+            parse_options,
+        })
+    }
+
+    /// Returns the value of the last occurrence of `key`, matching `read_env_vars`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        last_key_index(&self.lines, key).and_then(|index| match &self.lines[index] {
+            parser::Line::KeyValue { value, .. } => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Replaces the last occurrence of `key` in place, or appends it.
+    pub fn set(&mut self, key: &str, value: &str) {
+        let options = MergeOptions {
+            parse_options: self.parse_options,
+            ..Default::default()
+        };
+        set_line(&mut self.lines, key, value, &options);
+    }
+
+    /// Removes every occurrence of `key`, returning whether any were present.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let len = self.lines.len();
+        self.lines.retain(
+            |line| !matches!(line, parser::Line::KeyValue { key: line_key, .. } if line_key == key),
+        );
+        self.lines.len() != len
+    }
+
+    /// Iterates over key/value pairs in file order. A duplicate key is yielded
+    /// once, at its last occurrence.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> + '_ {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, line)| match line {
+                parser::Line::KeyValue { key, value, .. }
+                    if last_key_index(&self.lines, key) == Some(index) =>
+                {
+                    Some((key.as_str(), value.as_str()))
+                }
+                _ => None,
+            })
+    }
+}
+
+impl std::str::FromStr for Env {
+    type Err = EnvsetError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        Env::parse(content, ParseOptions::default())
+    }
+}
+
+impl fmt::Display for Env {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut buffer = Vec::new();
+        print_lines(
+            &self.lines,
+            &mut buffer,
+            false,
+            QuoteStyle::default(),
+            false,
+            self.parse_options.comment_char,
+        )
+        .map_err(|_| fmt::Error)?;
+        f.write_str(&String::from_utf8_lossy(&buffer))
+    }
+}
+
+pub fn print_env_file_contents<W: Write>(
+    lines: &[parser::Line],
+    writer: &mut W,
+    quote_style: QuoteStyle,
+    shell_safe: bool,
+    comment_char: CommentChar,
+) -> std::io::Result<()> {
+    print_lines(lines, writer, false, quote_style, shell_safe, comment_char)
+}
+
+/// Like [`print_env_file_contents`], but pads keys so every `=` lines up in
+/// the same column.
+pub fn print_aligned_env_file_contents<W: Write>(
+    lines: &[parser::Line],
+    writer: &mut W,
+    quote_style: QuoteStyle,
+    shell_safe: bool,
+    comment_char: CommentChar,
+) -> std::io::Result<()> {
+    let key_width = lines
+        .iter()
+        .filter_map(|line| match line {
+            parser::Line::KeyValue {
+                key, export, set, ..
+            } => Some(key.chars().count() + key_prefix(*export, *set).len()),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    write_lines(
+        lines,
+        writer,
+        false,
+        quote_style,
+        shell_safe,
+        comment_char,
+        key_width,
+    )
+}
+
+pub fn update_env_file(
+    file_path: &str,
+    env_vars: &HashMap<String, String>,
+) -> Result<(), EnvsetError> {
+    // A missing file is created, but one that can't be read isn't overwritten
+    let content = match read_env_file_contents(file_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let lines = add_env_vars(&content, env_vars)?;
+    let mut buffer = Vec::new();
+    print_env_file_contents(
+        &lines,
+        &mut buffer,
+        QuoteStyle::default(),
+        false,
+        CommentChar::default(),
+    )?;
+    let output = apply_final_newline(
+        &String::from_utf8_lossy(&buffer),
+        &content,
+        FinalNewline::Keep,
+    );
+    let output = apply_line_ending(&output, &content, LineEnding::Keep, ParseOptions::default());
+    write_file_atomic(file_path, output.as_bytes())?;
+    Ok(())
+}
+
+/// Looks for `file_name` in `start` and then each of its ancestors, the way git
+/// finds `.git`, returning the first match. Only bare file names are searched
+/// for; paths with a directory component are returned as is if they exist.
+pub fn find_in_parents(file_name: &str, start: &Path) -> Option<PathBuf> {
+    let name = Path::new(file_name);
+    if name.components().count() != 1 {
+        return name.exists().then(|| name.to_path_buf());
+    }
+    start
+        .ancestors()
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Copies `file_path` to `backup_path` (defaulting to `<file>.bak`) if it exists.
+pub fn backup_env_file(file_path: &str, backup_path: Option<&str>) -> std::io::Result<()> {
+    if !Path::new(file_path).exists() {
+        return Ok(());
+    }
+    let backup_path = match backup_path {
+        Some(path) => path.to_string(),
+        None => format!("{}.bak", file_path),
+    };
+    fs::copy(file_path, backup_path)?;
+    Ok(())
+}
+
+/// Writes `contents` to a temp file next to `file_path` and renames it into place,
+/// so an interrupted write never leaves a truncated file behind. The temp file
+/// gets the existing file's permissions before anything is written to it, and a
+/// symlinked file is written through to its target rather than replaced.
+pub fn write_file_atomic(file_path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let path = fs::canonicalize(file_path).unwrap_or_else(|_| PathBuf::from(file_path));
+    let path = path.as_path();
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid file path: {}", file_path),
+        )
+    })?;
+    let temp_path = dir.join(format!(
+        ".{}.envset-{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
 
-        if let Some(index) = last_index {
-            lines[index] = parser::Line::KeyValue {
-                key: key.clone(),
-                value: value.clone(),
-                comment: None,
-            };
-        } else {
-            // If the key doesn't exist, add it at the end
-            lines.push(parser::Line::KeyValue {
-                key: key.clone(),
-                value: value.clone(),
-                comment: None,
-            });
+    let permissions = fs::metadata(path)
+        .ok()
+        .map(|metadata| metadata.permissions());
+    let result = (|| {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // Never let the new contents be readable by more people than the old
+        #[cfg(unix)]
+        if let Some(permissions) = &permissions {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(permissions.mode() & 0o777);
+        }
+        let mut file = options.open(&temp_path)?;
+        if let Some(permissions) = permissions {
+            // The mode above is narrowed by the umask, so set it exactly
+            file.set_permissions(permissions)?;
         }
+        file.write_all(contents)?;
+        file.sync_all()?;
+
+        fs::rename(&temp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
     }
+    result
+}
 
-    Ok(lines)
+/// Syntax of the vars piped to envset on stdin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum StdinFormat {
+    /// `KEY=value` lines, like a .env file
+    #[default]
+    Dotenv,
+    /// A flat JSON object, see `parse_json_vars`
+    Json,
 }
 
-pub fn print_env_file_contents<W: Write>(
-    lines: &[parser::Line],
-    writer: &mut W,
-) -> std::io::Result<()> {
-    print_lines(lines, writer, false);
-    Ok(())
+pub fn parse_stdin(
+    format: StdinFormat,
+    parse_options: ParseOptions,
+) -> Result<HashMap<String, String>, EnvsetError> {
+    parse_stdin_with_format(&mut io::stdin(), format, parse_options)
 }
 
-pub fn update_env_file(file_path: &str, env_vars: &HashMap<String, String>) -> std::io::Result<()> {
-    let content = read_env_file_contents(file_path).unwrap_or_default();
-    let lines = add_env_vars(&content, env_vars)?;
-    let mut buffer = Vec::new();
-    print_env_file_contents(&lines, &mut buffer)?;
-    fs::write(file_path, buffer)
+pub fn parse_stdin_with_format<R: Read>(
+    reader: &mut R,
+    format: StdinFormat,
+    parse_options: ParseOptions,
+) -> Result<HashMap<String, String>, EnvsetError> {
+    match format {
+        StdinFormat::Dotenv => parse_stdin_with_reader(reader, parse_options),
+        StdinFormat::Json => {
+            let mut buffer = String::new();
+            reader.read_to_string(&mut buffer)?;
+            parse_json_vars(&buffer).map_err(EnvsetError::Json)
+        }
+    }
 }
 
-pub fn parse_stdin() -> HashMap<String, String> {
-    parse_stdin_with_reader(&mut io::stdin())
+pub fn parse_stdin_with_reader<R: Read>(
+    reader: &mut R,
+    parse_options: ParseOptions,
+) -> Result<HashMap<String, String>, EnvsetError> {
+    let mut buffer = String::new();
+    reader.read_to_string(&mut buffer)?;
+    parse_env_content(buffer.strip_prefix(BOM).unwrap_or(&buffer), parse_options)
 }
 
-pub fn parse_stdin_with_reader<R: Read>(reader: &mut R) -> HashMap<String, String> {
+/// Reads a newline separated list of keys, skipping blank lines and `#` comments.
+pub fn parse_keys_with_reader<R: Read>(reader: &mut R) -> Result<Vec<String>, EnvsetError> {
     let mut buffer = String::new();
-    reader.read_to_string(&mut buffer).unwrap();
-    parse_env_content(&buffer)
+    reader.read_to_string(&mut buffer)?;
+    Ok(buffer
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Parses a flat JSON object such as `{"KEY": "value"}` into vars. Numbers and
+/// booleans are written as their JSON text and `null` as an empty value; nested
+/// objects and arrays are rejected.
+pub fn parse_json_vars(content: &str) -> Result<HashMap<String, String>, String> {
+    let json: serde_json::Value =
+        serde_json::from_str(content).map_err(|e| format!("Invalid JSON input: {}", e))?;
+    let object = json.as_object().ok_or_else(|| {
+        format!(
+            "Expected a JSON object, found {}",
+            json.to_string().bold().red()
+        )
+    })?;
+
+    object
+        .iter()
+        .map(|(key, value)| {
+            if validate_key(key).is_err() {
+                return Err(format!("Invalid key {} in JSON input", key.bold().red()));
+            }
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Null => String::new(),
+                serde_json::Value::Bool(_) | serde_json::Value::Number(_) => value.to_string(),
+                serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+                    return Err(format!(
+                        "Value for {} must be a string, number, boolean or null, not a nested {}",
+                        key.bold().red(),
+                        if value.is_array() { "array" } else { "object" }
+                    ))
+                }
+            };
+            Ok((key.clone(), value))
+        })
+        .collect()
 }
 
 pub fn parse_args(vars: &[String]) -> Result<HashMap<String, String>, String> {
     vars.iter().try_fold(HashMap::new(), |mut acc, arg| {
         let parts: Vec<&str> = arg.splitn(2, '=').collect();
         if parts.len() == 2 {
-            let key = parser::key_parser()
-                .parse(parts[0])
-                .map_err(|_| format!("Invalid key format in argument: {}", arg.bold().red()))?;
-            acc.insert(key, parts[1].to_string());
+            validate_key(parts[0]).map_err(|_| {
+                format!(
+                    "Invalid key {} in argument {}. Keys must start with a letter or underscore \
+                     and contain only letters, digits and underscores",
+                    format!("{:?}", parts[0]).bold().red(),
+                    arg.bold()
+                )
+            })?;
+            acc.insert(parts[0].to_string(), parse_arg_value(parts[1])?);
             Ok(acc)
         } else {
             Err(format!(
@@ -146,183 +1626,966 @@ pub fn parse_args(vars: &[String]) -> Result<HashMap<String, String>, String> {
     })
 }
 
-pub fn parse_env_content(content: &str) -> HashMap<String, String> {
-    match parser::parser().parse(content) {
-        Ok(lines) => lines
-            .into_iter()
-            .filter_map(|line| {
-                if let parser::Line::KeyValue { key, value, .. } = line {
-                    Some((key, value))
-                } else {
-                    None
+/// Resolves `@path` values to the contents of the file at `path`, with `@@` as an
+/// escape for a literal leading `@`.
+fn parse_arg_value(value: &str) -> Result<String, String> {
+    if let Some(escaped) = value.strip_prefix("@@") {
+        Ok(format!("@{}", escaped))
+    } else if let Some(path) = value.strip_prefix('@') {
+        fs::read_to_string(path).map_err(|e| {
+            format!(
+                "Could not read value from file {}: {}",
+                path.bold().red(),
+                e
+            )
+        })
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+pub fn parse_env_content(
+    content: &str,
+    parse_options: ParseOptions,
+) -> Result<HashMap<String, String>, EnvsetError> {
+    Ok(lines_to_map(&parse_lines(content, parse_options)?))
+}
+
+/// Collects the keys and values of parsed lines into a map, ignoring comments
+/// and blank lines. A key set more than once keeps its last value, which is how
+/// every command in envset resolves duplicates.
+pub fn lines_to_map(lines: &[Line]) -> HashMap<String, String> {
+    key_values(lines).collect()
+}
+
+/// Like `lines_to_map`, but sorted by key.
+pub fn lines_to_sorted(lines: &[Line]) -> BTreeMap<String, String> {
+    key_values(lines).collect()
+}
+
+/// The key/value pairs of the lines in file order, duplicates included, so
+/// collecting them into a map lets the last value win.
+fn key_values(lines: &[Line]) -> impl Iterator<Item = (String, String)> + '_ {
+    lines.iter().filter_map(|line| match line {
+        Line::KeyValue { key, value, .. } => Some((key.clone(), value.clone())),
+        _ => None,
+    })
+}
+
+/// Restricts output to keys matching a prefix and/or a glob pattern.
+#[derive(Debug, Clone, Default)]
+pub struct KeyFilter {
+    pub prefix: Option<String>,
+    /// Pattern where `*` matches any run of characters and `?` a single one
+    pub glob: Option<String>,
+}
+
+impl KeyFilter {
+    pub fn is_active(&self) -> bool {
+        self.prefix.is_some() || self.glob.is_some()
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        self.prefix
+            .as_ref()
+            .is_none_or(|prefix| key.starts_with(prefix.as_str()))
+            && self
+                .glob
+                .as_ref()
+                .is_none_or(|pattern| glob_matches(pattern, key))
+    }
+}
+
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Removes a namespace prefix from keys when printing, e.g. `APP_DB_HOST`
+/// becomes `DB_HOST` with the prefix `APP_`.
+#[derive(Debug, Clone, Default)]
+pub struct PrefixStrip {
+    pub prefix: String,
+    /// Drop keys without the prefix instead of printing them unchanged
+    pub only_matching: bool,
+}
+
+impl PrefixStrip {
+    /// Returns the key to print in place of `key`, or `None` if it should be
+    /// left out. A key that is just the prefix doesn't count as matching.
+    pub fn apply(&self, key: &str) -> Option<String> {
+        match key.strip_prefix(self.prefix.as_str()) {
+            Some(stripped) if !stripped.is_empty() => Some(stripped.to_string()),
+            _ if self.only_matching => None,
+            _ => Some(key.to_string()),
+        }
+    }
+}
+
+/// Options controlling how `print_env_vars` displays a file.
+#[derive(Debug, Clone, Default)]
+pub struct PrintOptions {
+    pub use_color: bool,
+    pub quote_style: QuoteStyle,
+    /// Escape `$` and `` ` `` in double-quoted values
+    pub shell_safe: bool,
+    /// When active, only matching keys are printed and comments are dropped
+    pub filter: KeyFilter,
+    /// Sort keys for display, moving comments along with the key below them
+    pub sort: bool,
+    /// What to order by when sorting
+    pub sort_by: SortBy,
+    /// Expand `${NAME}` references in values
+    pub expand: bool,
+    /// With `expand`, fail on references that can't be resolved instead of
+    /// leaving them as written
+    pub strict_expand: bool,
+    /// When non-empty, print only these keys in this order, dropping comments
+    pub only: Vec<String>,
+    /// Env files merged on top of the file before printing, later ones winning
+    pub overlays: Vec<String>,
+    /// Replace values with `****`, revealing this many trailing characters
+    pub mask: Option<usize>,
+    /// Glob patterns restricting `mask` to matching keys; empty masks every key
+    pub mask_keys: Vec<String>,
+    /// Prefix removed from keys in the output, applied after every other option
+    /// so filters and `only` still use the keys as written in the file
+    pub strip_prefix: Option<PrefixStrip>,
+    /// How the file and its overlays are parsed
+    pub parse_options: ParseOptions,
+}
+
+pub fn print_env_vars<'a, W: Write>(
+    source: impl Into<EnvSource<'a>>,
+    writer: &mut W,
+    options: &PrintOptions,
+) -> Result<(), EnvsetError> {
+    let content = source.into().read()?;
+    print_env_lines(
+        parse_lines(&content, options.parse_options)?,
+        writer,
+        options,
+    )
+}
+
+/// Prints already parsed lines the way `print_env_vars` prints a file.
+pub fn print_env_lines<W: Write>(
+    mut lines: Vec<parser::Line>,
+    writer: &mut W,
+    options: &PrintOptions,
+) -> Result<(), EnvsetError> {
+    for overlay in &options.overlays {
+        let merge_options = MergeOptions {
+            parse_options: options.parse_options,
+            ..Default::default()
+        };
+        for (key, value) in read_env_vars_ordered(overlay, options.parse_options)? {
+            set_line(&mut lines, &key, &value, &merge_options);
+        }
+    }
+
+    if options.expand {
+        expand_lines(&mut lines, options.strict_expand)?;
+    }
+    if options.filter.is_active() {
+        lines.retain(|line| match line {
+            parser::Line::KeyValue { key, .. } => options.filter.matches(key),
+            _ => false,
+        });
+    }
+    if options.sort {
+        lines.retain(|line| !matches!(line, parser::Line::Blank));
+        lines = sort_lines(lines, false, options.sort_by);
+    }
+
+    let mut missing = Vec::new();
+    if !options.only.is_empty() {
+        let mut projected = Vec::new();
+        for key in &options.only {
+            match last_key_index(&lines, key) {
+                Some(index) => projected.push(lines[index].clone()),
+                None => missing.push(key.clone()),
+            }
+        }
+        lines = projected;
+    }
+
+    if let Some(reveal) = options.mask {
+        for line in lines.iter_mut() {
+            if let parser::Line::KeyValue {
+                key,
+                value,
+                quoting,
+                ..
+            } = line
+            {
+                let secret = options.mask_keys.is_empty()
+                    || options
+                        .mask_keys
+                        .iter()
+                        .any(|pattern| glob_matches(pattern, key));
+                if !secret {
+                    continue;
                 }
-            })
-            .collect(),
-        Err(e) => {
-            eprintln!("Error parsing .env content: {:?}", e);
-            HashMap::new()
+                *value = mask_value(value, reveal);
+                *quoting = Some(parser::Quoting::Unquoted);
+            }
         }
     }
+
+    if let Some(strip) = &options.strip_prefix {
+        lines = lines
+            .into_iter()
+            .filter_map(|line| match line {
+                parser::Line::KeyValue {
+                    key,
+                    value,
+                    comment,
+                    quoting,
+                    export,
+                    set,
+                    raw_value,
+                } => strip.apply(&key).map(|key| parser::Line::KeyValue {
+                    key,
+                    value,
+                    comment,
+                    quoting,
+                    export,
+                    set,
+                    raw_value,
+                }),
+                line => Some(line),
+            })
+            .collect();
+    }
+
+    print_lines(
+        &lines,
+        writer,
+        options.use_color,
+        options.quote_style,
+        options.shell_safe,
+        options.parse_options.comment_char,
+    )?;
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(EnvsetError::MissingKeys(missing))
+    }
+}
+
+/// Hides a value behind `****`, keeping the last `reveal` characters visible.
+/// Empty values stay empty so unset keys can still be told apart, and values
+/// too short to keep anything hidden are masked completely.
+fn mask_value(value: &str, reveal: usize) -> String {
+    if value.is_empty() {
+        return String::new();
+    }
+    let chars: Vec<char> = value.chars().collect();
+    if reveal == 0 || chars.len() <= reveal {
+        return "****".to_string();
+    }
+    let revealed: String = chars[chars.len() - reveal..].iter().collect();
+    format!("****{}", revealed)
 }
 
-pub fn print_env_vars<W: Write>(file_path: &str, writer: &mut W, use_color: bool) {
-    match fs::read_to_string(file_path) {
-        Ok(content) => match parser::parser().parse(content) {
-            Ok(lines) => {
-                print_lines(&lines, writer, use_color);
+/// Expands `${NAME}` references in values. A name resolves to the value of a key
+/// defined earlier in the file, falling back to the process environment.
+/// Single-quoted values are literal and left alone.
+fn expand_lines(lines: &mut [parser::Line], strict: bool) -> Result<(), EnvsetError> {
+    let mut defined: HashMap<String, String> = HashMap::new();
+    for line in lines.iter_mut() {
+        if let parser::Line::KeyValue {
+            key,
+            value,
+            quoting,
+            ..
+        } = line
+        {
+            if *quoting != Some(parser::Quoting::Single) {
+                *value = expand_value(value, &defined, strict).map_err(|name| {
+                    EnvsetError::UndefinedVariable {
+                        name,
+                        key: key.clone(),
+                    }
+                })?;
             }
-            Err(e) => {
-                eprintln!("Error parsing .env file: {:?}", e);
+            defined.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Renders `template`, replacing each `${NAME}` placeholder with NAME's value
+/// from `env_vars` or, failing that, the process environment. A placeholder
+/// with no value is an error unless `allow_missing` is set, in which case it is
+/// left as written.
+pub fn render_template(
+    template: &str,
+    env_vars: &HashMap<String, String>,
+    allow_missing: bool,
+) -> Result<String, EnvsetError> {
+    let mut rendered = String::with_capacity(template.len());
+    for (index, line) in template.split_inclusive('\n').enumerate() {
+        let line = expand_value(line, env_vars, !allow_missing).map_err(|name| {
+            EnvsetError::UndefinedPlaceholder {
+                name,
+                line: index + 1,
             }
-        },
-        Err(_) => {
-            eprintln!("Error reading .env file");
+        })?;
+        rendered.push_str(&line);
+    }
+    Ok(rendered)
+}
+
+/// Expands the references in a single value, returning the name of the first
+/// unresolved reference as the error when `strict` is set.
+fn expand_value(
+    value: &str,
+    defined: &HashMap<String, String>,
+    strict: bool,
+) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let end = start + 2 + len + 1;
+        match defined
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var(name).ok())
+        {
+            Some(resolved) => expanded.push_str(&resolved),
+            None if strict => return Err(name.to_string()),
+            None => expanded.push_str(&rest[start..end]),
         }
+        rest = &rest[end..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+pub fn print_lines<W: Write>(
+    lines: &[parser::Line],
+    writer: &mut W,
+    use_color: bool,
+    quote_style: QuoteStyle,
+    shell_safe: bool,
+    comment_char: CommentChar,
+) -> io::Result<()> {
+    write_lines(
+        lines,
+        writer,
+        use_color,
+        quote_style,
+        shell_safe,
+        comment_char,
+        0,
+    )
+}
+
+/// The keyword written before a key, if any.
+fn key_prefix(export: bool, set: bool) -> &'static str {
+    if set {
+        "set "
+    } else if export {
+        "export "
+    } else {
+        ""
     }
 }
 
-pub fn print_lines<W: Write>(lines: &[parser::Line], writer: &mut W, use_color: bool) {
+/// Writes the lines, padding `export KEY` out to `key_width` characters.
+fn write_lines<W: Write>(
+    lines: &[parser::Line],
+    writer: &mut W,
+    use_color: bool,
+    quote_style: QuoteStyle,
+    shell_safe: bool,
+    comment_char: CommentChar,
+    key_width: usize,
+) -> io::Result<()> {
+    let comment_char = comment_char.as_char();
     for line in lines {
         match line {
             parser::Line::Comment(comment) => {
                 let comment_str = if use_color {
-                    format!("#{}", comment).bright_black().to_string()
+                    format!("{}{}", comment_char, comment)
+                        .bright_black()
+                        .to_string()
                 } else {
-                    format!("#{}", comment)
+                    format!("{}{}", comment_char, comment)
                 };
-                writeln!(writer, "{}", comment_str).unwrap();
+                writeln!(writer, "{}", comment_str)?;
+            }
+            parser::Line::Blank => {
+                writeln!(writer)?;
             }
             parser::Line::KeyValue {
                 key,
                 value,
                 comment,
+                quoting,
+                export,
+                set,
+                raw_value,
             } => {
+                let prefix = key_prefix(*export, *set);
+                let padding =
+                    " ".repeat(key_width.saturating_sub(prefix.len() + key.chars().count()));
                 let key_str = if use_color {
                     key.blue().to_string()
                 } else {
                     key.to_string()
                 };
-                let quoted_value = quote_value(value);
+                let quoted_value = match quoting {
+                    Some(quoting) => requote_value(
+                        value,
+                        *quoting,
+                        raw_value.as_deref(),
+                        quote_style,
+                        shell_safe,
+                        comment_char,
+                    ),
+                    None => quote_value(value, quote_style, shell_safe, comment_char),
+                };
                 let value_str = if use_color {
                     quoted_value.green().to_string()
                 } else {
                     quoted_value
                 };
-                let mut line = format!("{}={}", key_str, value_str);
+                let mut line = format!("{}{}{}={}", prefix, key_str, padding, value_str);
                 if let Some(comment) = comment {
                     let comment_str = if use_color {
-                        format!(" #{}", comment).bright_black().to_string()
+                        format!(" {}{}", comment_char, comment)
+                            .bright_black()
+                            .to_string()
                     } else {
-                        format!(" #{}", comment)
+                        format!(" {}{}", comment_char, comment)
                     };
                     line.push_str(&comment_str);
                 }
-                writeln!(writer, "{}", line).unwrap();
+                writeln!(writer, "{}", line)?;
             }
         }
     }
+    Ok(())
 }
 
-pub fn print_env_keys_to_writer<W: Write>(file_path: &str, writer: &mut W) {
-    if let Ok(env_vars) = read_env_vars(file_path) {
-        for key in env_vars.keys() {
-            writeln!(writer, "{}", key).unwrap();
+pub fn print_env_keys_to_writer<'a, W: Write>(
+    source: impl Into<EnvSource<'a>>,
+    writer: &mut W,
+    filter: &KeyFilter,
+    parse_options: ParseOptions,
+) -> Result<(), EnvsetError> {
+    for (key, _) in read_env_vars_ordered(source, parse_options)? {
+        if filter.matches(&key) {
+            writeln!(writer, "{}", key)?;
         }
+    }
+    Ok(())
+}
+
+/// Prints the keys as a JSON array in file order.
+pub fn print_env_keys_as_json<'a, W: Write>(
+    source: impl Into<EnvSource<'a>>,
+    writer: &mut W,
+    filter: &KeyFilter,
+    parse_options: ParseOptions,
+) -> Result<(), EnvsetError> {
+    let keys: Vec<String> = read_env_vars_ordered(source, parse_options)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| filter.matches(key))
+        .collect();
+    writeln!(writer, "{}", serde_json::to_string_pretty(&keys).unwrap())?;
+    Ok(())
+}
+
+/// Prints the keys set in `example` that `lines` doesn't set, in example order,
+/// one per line or as a JSON array. Returns whether any key is missing.
+pub fn print_missing_keys<W: Write>(
+    example: &[Line],
+    lines: &[Line],
+    writer: &mut W,
+    filter: &KeyFilter,
+    json: bool,
+) -> io::Result<bool> {
+    let missing: Vec<String> = check_against_example(example, lines)
+        .missing
+        .into_iter()
+        .filter(|key| filter.matches(key))
+        .collect();
+    if json {
+        writeln!(
+            writer,
+            "{}",
+            serde_json::to_string_pretty(&missing).unwrap()
+        )?;
     } else {
-        eprintln!("Error reading .env file");
+        for key in &missing {
+            writeln!(writer, "{}", key)?;
+        }
     }
+    Ok(!missing.is_empty())
+}
+
+/// Prints the requested keys and their values as a JSON object. Keys that
+/// aren't set are left out, so a lookup of only missing keys prints `{}`.
+pub fn print_values_as_json<W: Write>(
+    env_vars: &HashMap<String, String>,
+    keys: &[String],
+    writer: &mut W,
+) -> io::Result<()> {
+    let values: serde_json::Map<String, serde_json::Value> = keys
+        .iter()
+        .filter_map(|key| env_vars.get(key).map(|value| (key.clone(), json!(value))))
+        .collect();
+    writeln!(writer, "{}", serde_json::to_string_pretty(&values).unwrap())
 }
 
+/// Removes every line setting one of `keys`, returning the remaining lines along
+/// with the keys that were found. With `tombstone`, each removed line is
+/// replaced by a `# removed: KEY` comment instead.
 pub fn delete_env_vars(
     content: &str,
     keys: &[String],
-) -> Result<Vec<parser::Line>, std::io::Error> {
-    let lines = parser::parser().parse(content).map_err(|e| {
-        std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            format!("Error parsing .env file: {:?}", e),
-        )
-    })?;
+    tombstone: bool,
+    parse_options: ParseOptions,
+) -> Result<(Vec<parser::Line>, Vec<String>), EnvsetError> {
+    let lines = parse_lines(content, parse_options)?;
+
+    let deleted: Vec<String> = keys
+        .iter()
+        .filter(|key| last_key_index(&lines, key).is_some())
+        .cloned()
+        .collect();
 
     let updated_lines: Vec<parser::Line> = lines
         .into_iter()
-        .filter(|line| {
-            if let parser::Line::KeyValue { key, .. } = line {
-                !keys.contains(key)
-            } else {
-                true
+        .filter_map(|line| match line {
+            parser::Line::KeyValue { key, .. } if keys.contains(&key) => {
+                tombstone.then(|| parser::Line::Comment(format!(" removed: {}", key)))
             }
+            line => Some(line),
         })
         .collect();
 
-    Ok(updated_lines)
+    Ok((updated_lines, deleted))
 }
 
-pub fn format_env_file(content: &str, prune: bool) -> Result<Vec<parser::Line>, std::io::Error> {
-    let lines = parser::parser().parse(content).map_err(|e| {
-        std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            format!("Error parsing .env file: {:?}", e),
-        )
-    })?;
+/// Removes every key with an empty value, returning the remaining lines along
+/// with the keys that were removed in file order.
+pub fn delete_empty_env_vars(
+    content: &str,
+    parse_options: ParseOptions,
+) -> Result<(Vec<parser::Line>, Vec<String>), EnvsetError> {
+    let lines = parse_lines(content, parse_options)?;
+
+    let mut deleted = Vec::new();
+    let updated_lines: Vec<parser::Line> = lines
+        .into_iter()
+        .filter(|line| match line {
+            parser::Line::KeyValue { key, value, .. } if value.is_empty() => {
+                if !deleted.contains(key) {
+                    deleted.push(key.clone());
+                }
+                false
+            }
+            _ => true,
+        })
+        .collect();
+
+    Ok((updated_lines, deleted))
+}
+
+/// Options controlling how `format_env_file` rewrites a file.
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// Remove whole line comments
+    pub prune: bool,
+    /// Keep only the last occurrence of each key
+    pub dedupe: bool,
+    /// Preserve the original key order instead of sorting
+    pub no_sort: bool,
+    /// Sort keys case-insensitively
+    pub ignore_case: bool,
+    /// What to order by when sorting
+    pub sort_by: SortBy,
+    /// Sort keys only within the blocks delimited by comments and blank lines
+    pub group: bool,
+    /// Change the case of every key
+    pub normalize_keys: KeyCase,
+    /// How the content is parsed
+    pub parse_options: ParseOptions,
+}
+
+pub fn format_env_file(
+    content: &str,
+    options: &FormatOptions,
+) -> Result<Vec<parser::Line>, EnvsetError> {
+    let mut lines = parse_lines(content, options.parse_options)?;
+
+    // fmt normalizes quoting, so drop the quoting recorded from the source
+    for line in lines.iter_mut() {
+        if let parser::Line::KeyValue { quoting, .. } = line {
+            *quoting = None;
+        }
+    }
+
+    if options.normalize_keys != KeyCase::None {
+        normalize_keys(&mut lines, options.normalize_keys)?;
+    }
+
+    if options.dedupe {
+        lines = dedupe_lines(lines);
+    }
+
+    // Pruning a file without any keys would leave nothing at all, so its
+    // comments are kept instead
+    let has_keys = lines
+        .iter()
+        .any(|line| matches!(line, parser::Line::KeyValue { value, .. } if !value.is_empty()));
+    let prune = options.prune && has_keys;
 
     let mut key_value_lines: Vec<parser::Line> = lines
         .into_iter()
         .filter(|line| match line {
             parser::Line::KeyValue { value, .. } => !value.is_empty(),
             parser::Line::Comment(_) => !prune,
+            // Sorting drops blank lines once they've detached the comments
+            // above them from the next key
+            parser::Line::Blank => true,
         })
         .collect();
 
-    key_value_lines.sort_by(|a, b| {
-        if let (
-            parser::Line::KeyValue { key: key_a, .. },
-            parser::Line::KeyValue { key: key_b, .. },
-        ) = (a, b)
-        {
-            key_a.cmp(key_b)
+    if options.no_sort {
+        key_value_lines = collapse_empty_lines(key_value_lines);
+    } else if options.group {
+        key_value_lines = sort_groups(
+            collapse_empty_lines(key_value_lines),
+            options.ignore_case,
+            options.sort_by,
+        );
+    } else {
+        key_value_lines = sort_lines(key_value_lines, options.ignore_case, options.sort_by);
+    }
+
+    Ok(key_value_lines)
+}
+
+/// Sorts keys without otherwise changing the file: empty values, comments and
+/// quoting are kept, with comments moving along with the key below them. Blank
+/// lines are dropped since their position has no meaning once sorted.
+pub fn sort_env_file(
+    content: &str,
+    ignore_case: bool,
+    sort_by: SortBy,
+    parse_options: ParseOptions,
+) -> Result<Vec<parser::Line>, EnvsetError> {
+    let lines = parse_lines(content, parse_options)?;
+    Ok(sort_lines(lines, ignore_case, sort_by))
+}
+
+/// Changes the case of every key, failing without changing anything if two
+/// differently spelled keys would end up the same.
+fn normalize_keys(lines: &mut [parser::Line], case: KeyCase) -> Result<(), EnvsetError> {
+    let normalize = |key: &str| match case {
+        KeyCase::None => key.to_string(),
+        KeyCase::Upper => key.to_uppercase(),
+        KeyCase::Lower => key.to_lowercase(),
+    };
+
+    let mut spellings: Vec<(String, Vec<String>)> = Vec::new();
+    for line in lines.iter() {
+        if let parser::Line::KeyValue { key, .. } = line {
+            let normalized = normalize(key);
+            match spellings.iter_mut().find(|(n, _)| *n == normalized) {
+                Some((_, originals)) if !originals.contains(key) => originals.push(key.clone()),
+                Some(_) => {}
+                None => spellings.push((normalized, vec![key.clone()])),
+            }
+        }
+    }
+    let collisions: Vec<_> = spellings
+        .into_iter()
+        .filter(|(_, originals)| originals.len() > 1)
+        .collect();
+    if !collisions.is_empty() {
+        return Err(EnvsetError::KeyCollisions(collisions));
+    }
+
+    for line in lines.iter_mut() {
+        if let parser::Line::KeyValue { key, .. } = line {
+            *key = normalize(key);
+        }
+    }
+    Ok(())
+}
+
+/// Sorts each run of consecutive `KeyValue` lines on its own, leaving comments
+/// and blank lines in place as section headers and separators.
+fn sort_groups(lines: Vec<parser::Line>, ignore_case: bool, sort_by: SortBy) -> Vec<parser::Line> {
+    let mut sorted = Vec::new();
+    let mut group = Vec::new();
+    for line in lines {
+        if matches!(line, parser::Line::KeyValue { .. }) {
+            group.push(line);
         } else {
-            std::cmp::Ordering::Equal
+            sorted.extend(sort_lines(std::mem::take(&mut group), ignore_case, sort_by));
+            sorted.push(line);
+        }
+    }
+    sorted.extend(sort_lines(group, ignore_case, sort_by));
+    sorted
+}
+
+/// Collapses runs of blank lines into one and drops leading and trailing blanks.
+fn collapse_empty_lines(lines: Vec<parser::Line>) -> Vec<parser::Line> {
+    let mut collapsed: Vec<parser::Line> = Vec::new();
+    for line in lines {
+        let is_empty = matches!(line, parser::Line::Blank);
+        let previous_empty = matches!(collapsed.last(), None | Some(parser::Line::Blank));
+        if !(is_empty && previous_empty) {
+            collapsed.push(line);
+        }
+    }
+    if matches!(collapsed.last(), Some(parser::Line::Blank)) {
+        collapsed.pop();
+    }
+    collapsed
+}
+
+/// Sorts `KeyValue` lines by key, or by value with the key as a tiebreaker.
+/// Whole-line comments directly above a key are treated as documenting it and
+/// move along with it; comments after the last key stay at the end. A blank
+/// line ends a comment's attachment, so comments followed by one, like section
+/// headers, are gathered at the top instead. Blank lines are dropped.
+fn sort_lines(lines: Vec<parser::Line>, ignore_case: bool, sort_by: SortBy) -> Vec<parser::Line> {
+    let mut blocks: Vec<(Vec<parser::Line>, parser::Line)> = Vec::new();
+    let mut detached = Vec::new();
+    let mut leading = Vec::new();
+    for line in lines {
+        match line {
+            parser::Line::KeyValue { .. } => {
+                // Only the comments after the last blank line belong to the key
+                let attached = match leading
+                    .iter()
+                    .rposition(|line| matches!(line, parser::Line::Blank))
+                {
+                    Some(blank) => leading.split_off(blank + 1),
+                    None => std::mem::take(&mut leading),
+                };
+                detached.extend(
+                    leading
+                        .drain(..)
+                        .filter(|line| !matches!(line, parser::Line::Blank)),
+                );
+                blocks.push((attached, line));
+            }
+            _ => leading.push(line),
         }
+    }
+    leading.retain(|line| !matches!(line, parser::Line::Blank));
+
+    blocks.sort_by(|(_, a), (_, b)| match (a, b) {
+        (
+            parser::Line::KeyValue {
+                key: key_a,
+                value: value_a,
+                ..
+            },
+            parser::Line::KeyValue {
+                key: key_b,
+                value: value_b,
+                ..
+            },
+        ) => match sort_by {
+            SortBy::Key => compare_keys(key_a, key_b, ignore_case),
+            SortBy::Value => compare_keys(value_a, value_b, ignore_case)
+                .then_with(|| compare_keys(key_a, key_b, ignore_case)),
+        },
+        _ => std::cmp::Ordering::Equal,
     });
 
-    Ok(key_value_lines)
+    let mut sorted = detached;
+    if !sorted.is_empty() && !blocks.is_empty() {
+        sorted.push(parser::Line::Blank);
+    }
+    for (comments, line) in blocks {
+        sorted.extend(comments);
+        sorted.push(line);
+    }
+    sorted.extend(leading);
+    sorted
+}
+
+fn compare_keys(a: &str, b: &str, ignore_case: bool) -> std::cmp::Ordering {
+    if ignore_case {
+        a.to_lowercase()
+            .cmp(&b.to_lowercase())
+            .then_with(|| a.cmp(b))
+    } else {
+        a.cmp(b)
+    }
 }
 
-fn needs_quoting(value: &str) -> bool {
+/// Drops every `KeyValue` line except the last one for each key, matching how
+/// `read_env_vars` resolves duplicates.
+fn dedupe_lines(lines: Vec<parser::Line>) -> Vec<parser::Line> {
+    let mut last_index = HashMap::new();
+    for (index, line) in lines.iter().enumerate() {
+        if let parser::Line::KeyValue { key, .. } = line {
+            last_index.insert(key.clone(), index);
+        }
+    }
+
+    lines
+        .into_iter()
+        .enumerate()
+        .filter(|(index, line)| match line {
+            parser::Line::KeyValue { key, .. } => last_index.get(key) == Some(index),
+            _ => true,
+        })
+        .map(|(_, line)| line)
+        .collect()
+}
+
+fn needs_quoting(value: &str, comment_char: char) -> bool {
     value.chars().any(|c| {
         c.is_whitespace()
             || c == '\''
             || c == '"'
             || c == '\\'
             || c == '$'
-            || c == '#'
             || c < ' '
             || c as u32 > 127
     }) || value.is_empty()
+        // A comment only starts at the beginning of a word, and whitespace is
+        // already quoted, so `a#b` can be written as is
+        || value.starts_with(comment_char)
 }
 
-fn quote_value(value: &str) -> String {
-    if needs_quoting(value) {
-        let mut quoted = String::with_capacity(value.len() + 2);
-        quoted.push('"');
-        for c in value.chars() {
-            match c {
-                '"' | '\\' => {
-                    quoted.push('\\');
-                    quoted.push(c);
-                }
-                '\n' | '\r' | '\t' => {
-                    quoted.push(c);
-                }
-                _ => {
-                    quoted.push(c);
-                }
+fn quote_value(
+    value: &str,
+    quote_style: QuoteStyle,
+    shell_safe: bool,
+    comment_char: char,
+) -> String {
+    match quote_style {
+        QuoteStyle::Always => double_quote(value, shell_safe),
+        QuoteStyle::Single if needs_quoting(value, comment_char) && single_quotable(value) => {
+            format!("'{}'", value)
+        }
+        _ if needs_quoting(value, comment_char) => double_quote(value, shell_safe),
+        _ => value.to_string(),
+    }
+}
+
+/// Whether `value` reads back unchanged from single quotes, which have no
+/// escapes: it can't hold a `'`, and trailing newlines would be trimmed.
+fn single_quotable(value: &str) -> bool {
+    !value.contains('\'') && !value[value.trim_end().len()..].contains('\n')
+}
+
+/// Reproduces the quoting a value had in the source file, falling back to the
+/// output policy when the value can't be represented that way.
+fn requote_value(
+    value: &str,
+    quoting: parser::Quoting,
+    raw_value: Option<&str>,
+    quote_style: QuoteStyle,
+    shell_safe: bool,
+    comment_char: char,
+) -> String {
+    // An unquoted value that still reads back the same is written exactly as
+    // it was, escapes included, rather than re-quoted
+    if let (parser::Quoting::Unquoted, Some(raw), false) = (quoting, raw_value, shell_safe) {
+        if unescape_unquoted(raw) == value {
+            return raw.to_string();
+        }
+    }
+    match quoting {
+        parser::Quoting::Double => double_quote(value, shell_safe),
+        parser::Quoting::Single if quote_style == QuoteStyle::Double => {
+            double_quote(value, shell_safe)
+        }
+        parser::Quoting::Single if !value.contains('\'') => format!("'{}'", value),
+        parser::Quoting::Unquoted if value.is_empty() || !needs_quoting(value, comment_char) => {
+            value.to_string()
+        }
+        _ => quote_value(value, quote_style, shell_safe, comment_char),
+    }
+}
+
+/// Decodes an unquoted value as the parser does, where a backslash escapes
+/// the character after it.
+fn unescape_unquoted(raw: &str) -> String {
+    let mut value = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.extend(chars.next()),
+            c => value.push(c),
+        }
+    }
+    value
+}
+
+/// Wraps `value` in double quotes, escaping `$` and `` ` `` too when
+/// `shell_safe` so a shell sourcing the file doesn't expand them.
+fn double_quote(value: &str, shell_safe: bool) -> String {
+    // The parser trims trailing whitespace that holds a literal newline, so
+    // newlines at the end of the value are escaped to survive a round trip
+    let tail_start = value.trim_end().len();
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for (i, c) in value.char_indices() {
+        match c {
+            '\n' if i >= tail_start => quoted.push_str("\\n"),
+            '\r' if i >= tail_start => quoted.push_str("\\r"),
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '$' | '`' if shell_safe => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' | '\r' | '\t' => {
+                // Written literally so multi-line values stay readable
+                quoted.push(c);
+            }
+            _ => {
+                quoted.push(c);
             }
         }
-        quoted.push('"');
-        quoted
-    } else {
-        value.to_string()
     }
+    quoted.push('"');
+    quoted
 }
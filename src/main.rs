@@ -1,17 +1,44 @@
 use atty::Stream;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use colored::Colorize;
 use similar::{ChangeTag, TextDiff};
 use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
 use std::process;
 
 use envset::{
-    add_env_vars, parse_args, parse_stdin, print_env_file_contents, print_env_keys_to_writer,
-    print_env_vars, print_env_vars_as_json, print_parse_tree, read_env_file_contents,
-    read_env_vars,
+    append_env_vars, apply_final_newline, apply_line_ending, backup_env_file,
+    check_against_example, count_env_file, delete_empty_env_vars, delete_env_vars, diff_envs,
+    find_in_parents, find_suspicious_values, has_bom, increment_env_var, lines_to_map,
+    lint_env_content, looks_secret, merge_env_vars, parse_args, parse_keys_with_reader,
+    parse_lines, parse_lines_best_effort, parse_stdin, print_aligned_env_file_contents,
+    print_env_file_contents, print_env_keys_as_json, print_env_keys_to_writer, print_env_lines,
+    print_env_vars, print_env_vars_as_export, print_env_vars_as_json,
+    print_env_vars_as_json_with_comments, print_env_vars_as_toml, print_env_vars_as_yaml,
+    print_env_vars_canonical, print_missing_keys, print_parse_tree, print_porcelain_diff,
+    print_values_as_json, read_env_file_contents, read_env_vars, read_env_vars_ordered,
+    read_env_vars_with_overlays, read_raw_env_vars_with_overlays, render_template, sort_env_file,
+    validate_env_content, validate_key, write_file_atomic, CommentChar, Dialect, EnvDiff,
+    EnvSource, EnvsetError, FinalNewline, FormatOptions, KeyCase, KeyFilter, Line, LineEnding,
+    LintRule, MergeOptions, ParseOptions, PrefixStrip, PrintOptions, QuoteStyle, SortBy,
+    StdinFormat, BOM, STDIN_PATH,
 };
 
-fn print_diff(old_content: &str, new_content: &str, use_color: bool) {
+/// Exit code when a requested key isn't there, there was nothing to do, or a
+/// comparison found differences
+const EXIT_NOT_FOUND: i32 = 1;
+/// Exit code for `--exit-changed` when the file was modified
+const EXIT_CHANGED: i32 = 10;
+/// Exit code for usage, parse and IO errors, the same code clap uses for bad arguments
+const EXIT_ERROR: i32 = 2;
+
+fn print_diff<W: Write>(
+    out: &mut W,
+    old_content: &str,
+    new_content: &str,
+    use_color: bool,
+) -> std::io::Result<()> {
     let diff = TextDiff::from_lines(old_content, new_content);
     let term_width = term_size::dimensions().map(|(w, _)| w).unwrap_or(80);
 
@@ -21,22 +48,24 @@ fn print_diff(old_content: &str, new_content: &str, use_color: bool) {
                 ChangeTag::Delete => {
                     let line = change.to_string();
                     let padding = " ".repeat(term_width.saturating_sub(line.trim_end().len()));
-                    print!(
+                    write!(
+                        out,
                         "{}",
                         (line.trim_end().to_string() + &padding).on_bright_red()
-                    );
-                    println!();
+                    )?;
+                    writeln!(out)?;
                 }
                 ChangeTag::Insert => {
                     let line = change.to_string();
                     let padding = " ".repeat(term_width.saturating_sub(line.trim_end().len()));
-                    print!(
+                    write!(
+                        out,
                         "{}",
                         (line.trim_end().to_string() + &padding).on_bright_green()
-                    );
-                    println!();
+                    )?;
+                    writeln!(out)?;
                 }
-                ChangeTag::Equal => print!("{}", change),
+                ChangeTag::Equal => write!(out, "{}", change)?,
             }
         } else {
             let sign = match change.tag() {
@@ -44,9 +73,284 @@ fn print_diff(old_content: &str, new_content: &str, use_color: bool) {
                 ChangeTag::Insert => "+",
                 ChangeTag::Equal => " ",
             };
-            print!("{}{}", sign, change);
+            write!(out, "{}{}", sign, change)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the final newline and line ending policies to freshly printed output.
+fn finish_output(cli: &Cli, old_content: &str, buffer: &[u8]) -> String {
+    let output = apply_final_newline(
+        &String::from_utf8_lossy(buffer),
+        old_content,
+        cli.final_newline,
+    );
+    apply_line_ending(&output, old_content, cli.line_ending, cli.parse_options())
+}
+
+/// Reads the env file for a command that treats a missing file as empty. Any
+/// other error exits, so a file that can't be read is never overwritten.
+fn read_existing_env_file(cli: &Cli) -> String {
+    match cli.source().read() {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            eprintln!("Error reading {}: {}", cli.file, e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// Writes the new file contents, or prints them with `--stdout`, and returns
+/// whether they differ from `old_content`.
+fn write_env_file(cli: &Cli, old_content: &str, buffer: Vec<u8>) -> bool {
+    write_env_file_to(cli, old_content, buffer, &mut std::io::stdout())
+}
+
+/// Does the work of `write_env_file`, printing the diff, or the new contents
+/// with `--stdout`, to `out`. The file is only written when neither `--stdout`
+/// nor `--dry-run` is given.
+fn write_env_file_to<W: Write>(cli: &Cli, old_content: &str, buffer: Vec<u8>, out: &mut W) -> bool {
+    // `old_content` was read with any byte order mark stripped, so compare and
+    // diff against the new content before one is added back
+    let new_content = finish_output(cli, old_content, &buffer);
+    let changed = new_content != old_content;
+    let mut buffer = new_content.clone().into_bytes();
+    if cli.keep_bom && has_bom(&cli.file) {
+        buffer.splice(0..0, BOM.to_string().into_bytes());
+    }
+
+    if cli.stdout {
+        if let Err(e) = out.write_all(&buffer) {
+            eprintln!("Error writing to stdout: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+        return changed;
+    }
+
+    if cli.porcelain {
+        let parse = |content: &str, which: &str| {
+            parse_lines(content, cli.parse_options()).unwrap_or_else(|e| {
+                eprintln!(
+                    "Error parsing the {} .env file for --porcelain: {}",
+                    which, e
+                );
+                process::exit(EXIT_ERROR);
+            })
+        };
+        let diff = diff_envs(&parse(old_content, "current"), &parse(&new_content, "new"));
+        if let Err(e) = print_porcelain_diff(&diff, out) {
+            eprintln!("Error writing to stdout: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    } else if let Err(e) = print_diff(out, old_content, &new_content, cli.use_color()) {
+        eprintln!("Error writing to stdout: {}", e);
+        process::exit(EXIT_ERROR);
+    }
+
+    if cli.dry_run {
+        return changed;
+    }
+
+    if let Some(backup_path) = &cli.backup {
+        if let Err(e) = backup_env_file(&cli.file, backup_path.as_deref()) {
+            eprintln!("Error backing up .env file: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+
+    if let Err(e) = write_file_atomic(&cli.file, &buffer) {
+        eprintln!("Error writing .env file: {}", e);
+        process::exit(EXIT_ERROR);
+    }
+
+    changed
+}
+
+/// Prints the keys added, removed and changed in `diff`, and returns whether
+/// there were any differences.
+fn print_env_diff(diff: &EnvDiff, use_color: bool) -> bool {
+    let paint = |text: String, color: &str| {
+        if use_color {
+            text.color(color).to_string()
+        } else {
+            text
+        }
+    };
+
+    if !diff.added.is_empty() {
+        println!("Added:");
+        for (key, value) in &diff.added {
+            println!("{}", paint(format!("  + {}={}", key, value), "green"));
+        }
+    }
+    if !diff.removed.is_empty() {
+        println!("Removed:");
+        for (key, value) in &diff.removed {
+            println!("{}", paint(format!("  - {}={}", key, value), "red"));
+        }
+    }
+    if !diff.changed.is_empty() {
+        println!("Changed:");
+        for (key, old_value, new_value) in &diff.changed {
+            println!(
+                "{}",
+                paint(
+                    format!("  ~ {}: {} → {}", key, old_value, new_value),
+                    "yellow"
+                )
+            );
+        }
+    }
+
+    !diff.is_empty()
+}
+
+/// Asks on the terminal for a value for each of `keys`, offering the current
+/// value in `file` as the default. Input is hidden for keys that look like
+/// secrets, and their current values aren't shown.
+fn prompt_for_values(
+    file: &str,
+    keys: &[String],
+    parse_options: ParseOptions,
+) -> HashMap<String, String> {
+    if !atty::is(Stream::Stdin) {
+        eprintln!("--interactive needs a terminal to prompt on");
+        process::exit(EXIT_ERROR);
+    }
+    if keys.is_empty() {
+        eprintln!("No keys to prompt for. Pass them as arguments, e.g. envset --interactive FOO");
+        process::exit(EXIT_ERROR);
+    }
+    for key in keys {
+        if let Err(e) = validate_key(key) {
+            eprintln!("Error: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+    let current = read_env_vars(file, parse_options).unwrap_or_else(|e| {
+        eprintln!("Error reading .env file: {}", e);
+        process::exit(EXIT_ERROR);
+    });
+
+    let mut values = HashMap::new();
+    for key in keys {
+        let secret = looks_secret(key);
+        let prompt = match current.get(key) {
+            Some(_) if secret => format!("{} [keep current]: ", key),
+            Some(value) => format!("{} [{}]: ", key, value),
+            None => format!("{}: ", key),
+        };
+        let input = if secret {
+            rpassword::prompt_password(&prompt)
+        } else {
+            eprint!("{}", prompt);
+            let mut line = String::new();
+            std::io::stderr()
+                .flush()
+                .and_then(|()| std::io::stdin().read_line(&mut line))
+                .and_then(|read| match read {
+                    0 => Err(std::io::ErrorKind::UnexpectedEof.into()),
+                    _ => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+                })
+        };
+        let input = input.unwrap_or_else(|e| {
+            eprintln!("\nNo value read for {}: {}", key, e);
+            process::exit(EXIT_ERROR);
+        });
+        let value = match current.get(key) {
+            Some(value) if input.is_empty() => value.clone(),
+            _ => input,
+        };
+        values.insert(key.clone(), value);
+    }
+    values
+}
+
+/// Reads and parses an env file, exiting with an error naming `name` if it
+/// can't be read or doesn't parse.
+fn read_lines(source: EnvSource, name: &str, parse_options: ParseOptions) -> Vec<Line> {
+    source
+        .read()
+        .map_err(EnvsetError::from)
+        .and_then(|content| parse_lines(&content, parse_options))
+        .unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", name, e);
+            process::exit(EXIT_ERROR);
+        })
+}
+
+/// Reads and parses the env file, skipping the lines that don't parse and
+/// listing them on stderr.
+fn read_lines_best_effort(source: EnvSource, parse_options: ParseOptions) -> Vec<Line> {
+    let content = source.read().unwrap_or_else(|e| {
+        eprintln!("Error reading .env file: {}", e);
+        process::exit(EXIT_ERROR);
+    });
+    let (lines, skipped) = parse_lines_best_effort(&content, parse_options);
+    for (line_number, error) in skipped {
+        eprintln!("Skipped line {}: {}", line_number, error);
+    }
+    lines
+}
+
+/// Opens a copy of `content` in `$EDITOR` (or `vi`, `notepad` on Windows) and
+/// returns the edited text. If the result no longer parses, the errors are
+/// printed, the copy is kept so the edits aren't lost, and the process exits.
+fn edit_in_editor(content: &str, parse_options: ParseOptions) -> std::io::Result<String> {
+    let editor = std::env::var("EDITOR")
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| if cfg!(windows) { "notepad" } else { "vi" }.to_string());
+    let mut editor_args = editor.split_whitespace();
+    let program = editor_args.next().unwrap_or_default();
+
+    // The copy holds secrets, so it's created readable only by the user and
+    // under a fresh name nobody else can have claimed in the shared temp dir
+    let mut edit_file = tempfile::Builder::new()
+        .prefix("envset-edit-")
+        .suffix(".env")
+        .tempfile()?;
+    edit_file.write_all(content.as_bytes())?;
+    let edit_path = edit_file.into_temp_path();
+
+    let status = process::Command::new(program)
+        .args(editor_args)
+        .arg(&edit_path)
+        .status()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("could not run {}: {}", editor, e)))?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} exited with {}, nothing was saved",
+            editor, status
+        )));
+    }
+
+    let edited = std::fs::read_to_string(&edit_path)?;
+    if let Err(errors) = validate_env_content(&edited, parse_options) {
+        for error in errors {
+            eprintln!("{}", error);
         }
+        eprintln!(
+            "The edited file doesn't parse, so it wasn't saved. Your edits are in {}",
+            edit_path.display()
+        );
+        let _ = edit_path.keep();
+        process::exit(EXIT_ERROR);
     }
+    edit_path.close()?;
+    Ok(edited)
+}
+
+fn print_completions<W: Write>(shell: clap_complete::Shell, writer: &mut W) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, writer);
+}
+
+fn print_man_page<W: Write>(writer: &mut W) -> std::io::Result<()> {
+    clap_mangen::Man::new(Cli::command()).render(writer)
 }
 
 #[cfg(test)]
@@ -58,19 +362,268 @@ struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
 
-    /// File path for the .env file
-    #[arg(short = 'f', long = "file", default_value = ".env", global = true)]
+    /// File path for the .env file, or - to read it from stdin (read-only commands only)
+    #[arg(
+        short = 'f',
+        long = "file",
+        env = "ENVSET_FILE",
+        default_value = ".env",
+        global = true
+    )]
     file: String,
 
-    /// KEY=value pairs to set
+    /// Print the resulting file to stdout instead of writing it
+    #[arg(long = "stdout", global = true)]
+    stdout: bool,
+
+    /// Show the diff of what would change without writing the file
+    #[arg(long = "dry-run", global = true)]
+    dry_run: bool,
+
+    /// If the file isn't in the current directory, use the nearest one found in a
+    /// parent directory
+    #[arg(long = "search-parents", global = true)]
+    search_parents: bool,
+
+    /// Keep the byte order mark at the start of a file that has one when writing
+    /// it, instead of dropping it
+    #[arg(long = "keep-bom", global = true)]
+    keep_bom: bool,
+
+    /// Read the env file as it was at this git revision, e.g. HEAD~3, instead
+    /// of from the working tree. Read-only commands only
+    #[arg(long = "git-rev", global = true, value_name = "REV")]
+    git_rev: Option<String>,
+
+    /// Print the absolute path of the env file that would be used, then exit
+    /// without reading or writing it
+    #[arg(long = "print-path", global = true)]
+    print_path: bool,
+
+    /// Report changes as stable `+KEY`, `-KEY` and `~KEY "old" "new"` lines
+    /// instead of a diff of the file
+    #[arg(long = "porcelain", global = true)]
+    porcelain: bool,
+
+    /// Copy the current file to <file>.bak (or the given path) before writing
+    #[arg(
+        long = "backup",
+        value_name = "PATH",
+        num_args = 0..=1,
+        require_equals = true,
+        global = true
+    )]
+    backup: Option<Option<String>>,
+
+    /// When to use colored output
+    #[arg(long = "color", value_enum, default_value_t = ColorChoice::Auto, global = true)]
+    color: ColorChoice,
+
+    /// How to quote values when writing the file
+    #[arg(long = "quote-style", value_enum, default_value_t = QuoteStyle::Minimal, global = true)]
+    quote_style: QuoteStyle,
+
+    /// Line endings to write, by default matching the existing file
+    #[arg(long = "line-ending", value_enum, default_value_t = LineEnding::Keep, global = true)]
+    line_ending: LineEnding,
+
+    /// Character that starts comments when reading and writing the file, e.g.
+    /// ';' for INI-style files
+    #[arg(long = "comment-char", value_enum, default_value_t = CommentChar::Hash, global = true)]
+    comment_char: CommentChar,
+
+    /// Escape `$` and backticks in double-quoted values when writing, so a shell
+    /// sourcing the file leaves them alone
+    #[arg(long = "shell-safe", global = true)]
+    shell_safe: bool,
+
+    /// Flavor of env file to read. With cmd, `set KEY=value` lines from batch
+    /// files are accepted, their `set ` is kept, and new keys get one too
+    #[arg(long = "dialect", value_enum, default_value_t = Dialect::Dotenv, global = true)]
+    dialect: Dialect,
+
+    /// Whether to end the file with a newline, by default matching the existing file
+    #[arg(long = "final-newline", value_enum, default_value_t = FinalNewline::Keep, global = true)]
+    final_newline: FinalNewline,
+
+    /// Only set keys that don't exist yet, leaving existing values untouched
+    #[arg(long = "no-overwrite", alias = "if-absent")]
+    no_overwrite: bool,
+
+    /// Append to the existing value instead of replacing it, e.g. for PATH-style vars
+    #[arg(long = "append", conflicts_with = "no_overwrite")]
+    append: bool,
+
+    /// Separator placed between the existing and appended value
+    #[arg(long = "separator", default_value = ":", requires = "append")]
+    separator: String,
+
+    /// Write the vars that are set with an `export ` prefix
+    #[arg(long = "export")]
+    export: bool,
+
+    /// Add a trailing comment to the vars that are set, e.g. `PORT=8080 # service port`
+    #[arg(long = "comment")]
+    comment: Option<String>,
+
+    /// Syntax of the vars piped on stdin. JSON must be a flat object whose values
+    /// are strings, numbers, booleans or null
+    #[arg(long = "stdin-format", value_enum, default_value_t = StdinFormat::Dotenv)]
+    stdin_format: StdinFormat,
+
+    /// Shorthand for --stdin-format json
+    #[arg(long = "from-json", conflicts_with = "stdin_format")]
+    from_json: bool,
+
+    /// Replace the whole file with the vars given instead of merging them in.
+    /// Piped dotenv content is written as is, comments and all
+    #[arg(
+        long = "replace",
+        alias = "replace-all",
+        conflicts_with_all = ["no_overwrite", "append"]
+    )]
+    replace: bool,
+
+    /// Exit with code 10 if setting the vars changed the file, 0 if it was
+    /// already up to date
+    #[arg(long = "exit-changed")]
+    exit_changed: bool,
+
+    /// Prompt on the terminal for the value of each KEY given as an argument,
+    /// hiding the input for keys that look like secrets
+    #[arg(long = "interactive", conflicts_with_all = ["replace", "append"])]
+    interactive: bool,
+
+    /// KEY=value pairs to set, or just keys with --interactive
     #[arg(required = false)]
     vars: Vec<String>,
+
+    /// The env file as it was at `--git-rev`, read in place of the file itself
+    #[arg(skip)]
+    git_content: Option<String>,
+}
+
+impl Cli {
+    /// Resolves `--color`, honoring `NO_COLOR` and only coloring a terminal in auto mode.
+    fn use_color(&self) -> bool {
+        match self.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+                !no_color && atty::is(Stream::Stdout)
+            }
+        }
+    }
+
+    /// Whether the command writes back to the env file.
+    fn writes_env_file(&self) -> bool {
+        match self.command {
+            Some(
+                Commands::Delete { .. }
+                | Commands::Fmt { check: false, .. }
+                | Commands::Sort { .. }
+                | Commands::Merge { .. }
+                | Commands::CheckExample { sync: true, .. }
+                | Commands::Incr { .. }
+                | Commands::Decr { .. }
+                | Commands::Edit,
+            ) => true,
+            None => !self.vars.is_empty() || self.replace,
+            _ => false,
+        }
+    }
+
+    /// How env files are parsed, from `--dialect` and `--comment-char`.
+    fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            dialect: self.dialect,
+            comment_char: self.comment_char,
+        }
+    }
+
+    /// Where read-only commands read the env file from: its content at
+    /// `--git-rev`, or the file itself.
+    fn source(&self) -> EnvSource<'_> {
+        match &self.git_content {
+            Some(content) => EnvSource::Content(content),
+            None => EnvSource::Path(&self.file),
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct FilterArgs {
+    /// Only include keys starting with this prefix
+    #[arg(long = "prefix")]
+    prefix: Option<String>,
+    /// Only include keys matching this glob pattern, e.g. 'AWS_*'
+    #[arg(long = "glob")]
+    glob: Option<String>,
+}
+
+impl FilterArgs {
+    fn key_filter(&self) -> KeyFilter {
+        KeyFilter {
+            prefix: self.prefix.clone(),
+            glob: self.glob.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum PrintFormat {
+    /// KEY=value lines, as stored in the .env file
+    Dotenv,
+    /// A JSON object, same as --json
+    Json,
+    /// A YAML mapping
+    Yaml,
+    /// A flat TOML table
+    Toml,
+    /// `export KEY='value'` lines that can be sourced by a POSIX shell
+    Export,
 }
 
 #[derive(clap::Subcommand)]
 enum Commands {
-    /// Get the value of a single environment variable
-    Get { key: String },
+    /// Get the value of one or more environment variables
+    Get {
+        /// Keys to look up. A single key prints its value, several print KEY=value lines
+        #[arg(required = true)]
+        keys: Vec<String>,
+        /// Print only the values, one per line, in the order requested
+        #[arg(long = "values-only")]
+        values_only: bool,
+        /// With --values-only, print an empty line for each missing key
+        #[arg(long = "keep-missing", requires = "values_only")]
+        keep_missing: bool,
+        /// Print the keys and values as a JSON object, leaving out missing keys
+        #[arg(long = "json", conflicts_with = "values_only")]
+        json: bool,
+        /// Exit non-zero if a key is set to an empty value, as if it were missing
+        #[arg(long = "fail-on-empty")]
+        fail_on_empty: bool,
+        /// Print values exactly as written in the file, without decoding escapes
+        /// like `\n`
+        #[arg(long = "raw")]
+        raw: bool,
+        /// Skip lines of the .env file that don't parse, listing them on stderr,
+        /// instead of failing
+        #[arg(long = "best-effort", conflicts_with = "raw")]
+        best_effort: bool,
+        /// Env file to apply on top of the .env file, e.g. .env.local. Repeatable,
+        /// with later files taking precedence
+        #[arg(long = "overlay")]
+        overlays: Vec<String>,
+    },
     /// Print all environment variables
     Print {
         /// Print the JSON representation of the parse tree
@@ -79,133 +632,1222 @@ enum Commands {
         /// Print the environment variables as a JSON object
         #[arg(short = 'j', long = "json")]
         json: bool,
+        /// With JSON output, print each key as an object holding its value and
+        /// trailing comment
+        #[arg(long = "with-comments")]
+        with_comments: bool,
+        /// With JSON output, sort the keys alphabetically instead of keeping
+        /// file order
+        #[arg(long = "sort-keys", conflicts_with = "with_comments")]
+        sort_keys: bool,
+        /// Output format
+        #[arg(long = "format", value_enum, default_value_t = PrintFormat::Dotenv)]
+        format: PrintFormat,
+        /// Sort keys in the dotenv output without changing the file
+        #[arg(long = "sort")]
+        sort: bool,
+        /// Sort the dotenv output by key or by value, implies --sort
+        #[arg(long = "sort-by", value_enum)]
+        sort_by: Option<SortBy>,
+        /// Expand ${NAME} references in the dotenv output, using keys defined earlier
+        /// in the file and then the process environment
+        #[arg(long = "expand")]
+        expand: bool,
+        /// With --expand, fail on references that can't be resolved
+        #[arg(long = "strict-expand", requires = "expand")]
+        strict_expand: bool,
+        /// Print only these comma separated keys in the dotenv output, in the order given
+        #[arg(long = "only", value_delimiter = ',')]
+        only: Vec<String>,
+        /// With --only, fail if any of the keys aren't found
+        #[arg(long = "strict", requires = "only")]
+        strict: bool,
+        /// Env file to apply on top of the .env file in the dotenv output, e.g.
+        /// .env.local. Repeatable, with later files taking precedence
+        #[arg(long = "overlay")]
+        overlays: Vec<String>,
+        /// Replace values with **** in the dotenv output, e.g. when screen sharing
+        #[arg(long = "mask")]
+        mask: bool,
+        /// Only mask keys matching one of these comma-separated globs, e.g.
+        /// "*_KEY,*_SECRET,PASSWORD". Implies --mask
+        #[arg(long = "mask-keys", value_name = "PATTERNS", value_delimiter = ',')]
+        mask_keys: Vec<String>,
+        /// Show this many trailing characters of each masked value. Implies --mask
+        #[arg(long = "mask-reveal", value_name = "N")]
+        mask_reveal: Option<usize>,
+        /// Remove this prefix from the keys that have it, e.g. APP_ turns
+        /// APP_DB_HOST into DB_HOST. Dotenv and export output only
+        #[arg(long = "prefix-strip", value_name = "PREFIX")]
+        prefix_strip: Option<String>,
+        /// With --prefix-strip, leave out keys that don't have the prefix
+        #[arg(long = "only-matching", requires = "prefix_strip")]
+        only_matching: bool,
+        /// Skip lines that don't parse, listing them on stderr, instead of
+        /// failing. Dotenv output only
+        #[arg(long = "best-effort")]
+        best_effort: bool,
+        /// Print a stable normal form for snapshot tests: sorted keys, every
+        /// value double-quoted and escaped onto one line, no comments
+        #[arg(long = "canonical", conflicts_with_all = [
+            "parse_tree", "json", "format", "sort", "sort_by", "expand", "only",
+            "overlays", "mask", "mask_keys", "mask_reveal", "prefix_strip",
+            "best_effort", "files",
+        ])]
+        canonical: bool,
+        /// Env files to print instead of the .env file, each under a
+        /// `# === path ===` header. Dotenv output only
+        #[arg(value_name = "FILE")]
+        files: Vec<String>,
+        #[command(flatten)]
+        filter: FilterArgs,
     },
     /// Print all keys in the .env file
-    Keys,
+    Keys {
+        /// Print the keys as a JSON array
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+        /// Print only the keys set in this example file that the .env file is
+        /// missing, exiting with 1 if there are any
+        #[arg(long = "missing", value_name = "EXAMPLE")]
+        missing: Option<String>,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Print the number of distinct keys in the .env file
+    Count {
+        /// Also report the number of whole-line comments and blank lines
+        #[arg(long = "with-comments")]
+        with_comments: bool,
+    },
     /// Delete specified environment variables
     #[command(alias = "rm")]
     Delete {
-        /// Keys to delete
-        #[arg(required = true)]
+        /// Keys to delete, read one per line from stdin when none are given
         keys: Vec<String>,
+        /// Succeed quietly when keys to delete don't exist
+        #[arg(long = "ignore-missing")]
+        ignore_missing: bool,
+        /// Delete every key with an empty value instead of naming keys
+        #[arg(long = "empty", conflicts_with = "keys")]
+        empty: bool,
+        /// Replace each deleted line with a `# removed: KEY` comment
+        #[arg(long = "tombstone", conflicts_with = "empty")]
+        tombstone: bool,
     },
     /// Format the .env file (sort keys and remove empty lines)
     Fmt {
         /// Remove whole line comments
         #[arg(short = 'p', long = "prune")]
         prune: bool,
+        /// Keep only the last occurrence of each key
+        #[arg(short = 'd', long = "dedupe")]
+        dedupe: bool,
+        /// Don't write anything, exit 1 with a diff if the file isn't formatted
+        #[arg(long = "check")]
+        check: bool,
+        /// Keep the original key order instead of sorting
+        #[arg(long = "no-sort")]
+        no_sort: bool,
+        /// Keep comments and blank lines in place, sorting keys only within the
+        /// sections between them
+        #[arg(long = "group", conflicts_with = "no_sort")]
+        group: bool,
+        /// Uppercase or lowercase every key, failing if two keys would collide
+        #[arg(long = "normalize-keys", value_enum, default_value_t = KeyCase::None)]
+        normalize_keys: KeyCase,
+        /// Sort keys case-insensitively
+        #[arg(short = 'i', long = "ignore-case")]
+        ignore_case: bool,
+        /// Order lines by key or by value
+        #[arg(long = "sort-by", value_enum, default_value_t = SortBy::Key)]
+        sort_by: SortBy,
+        /// Pad keys so the `=` of every line lines up in one column
+        #[arg(long = "align")]
+        align: bool,
+    },
+    /// Sort keys in the .env file, keeping empty values and comments
+    Sort {
+        /// Sort keys case-insensitively
+        #[arg(short = 'i', long = "ignore-case")]
+        ignore_case: bool,
+        /// Order lines by key or by value
+        #[arg(long = "sort-by", value_enum, default_value_t = SortBy::Key)]
+        sort_by: SortBy,
+    },
+    /// Check that the .env file parses, reporting errors with line numbers
+    Validate {
+        /// Also warn about values that look accidentally quoted twice or contain
+        /// stray quotes
+        #[arg(long = "warn-suspicious")]
+        warn_suspicious: bool,
     },
+    /// Report common problems like duplicate keys, lowercase keys and values
+    /// that should be quoted
+    Lint {
+        /// Treat these comma-separated rules as errors, exiting 1 if any of them
+        /// are found
+        #[arg(long = "deny", value_enum, value_name = "RULES", value_delimiter = ',')]
+        deny: Vec<LintRule>,
+    },
+    /// Compare the .env file with another env file
+    Diff {
+        /// The env file to compare against
+        #[arg(required_unless_present = "since")]
+        other: Option<String>,
+        /// Compare the file as it was at this git revision, e.g. HEAD or main,
+        /// against the working tree instead of against another file
+        #[arg(long = "since", value_name = "REV", conflicts_with_all = ["other", "git_rev"])]
+        since: Option<String>,
+        /// Exit 0 even if the files differ
+        #[arg(long = "exit-zero")]
+        exit_zero: bool,
+    },
+    /// Check that the .env file sets every key declared in an example file,
+    /// listing missing and extra keys
+    CheckExample {
+        /// The example file, whose values are ignored
+        #[arg(default_value = ".env.example")]
+        example: String,
+        /// Add the missing keys to the .env file with empty values
+        #[arg(long = "sync")]
+        sync: bool,
+    },
+    /// Merge another env file into the .env file, with its values taking precedence
+    Merge {
+        /// The env file to merge in
+        overlay: String,
+        /// Only add keys that don't exist yet, never overwrite existing values
+        #[arg(long = "only-new")]
+        only_new: bool,
+    },
+    /// Add to the integer value of a key, treating a missing key as 0
+    Incr {
+        /// The key to increment
+        key: String,
+        /// The amount to add
+        #[arg(long = "by", default_value_t = 1)]
+        by: i64,
+    },
+    /// Subtract from the integer value of a key, treating a missing key as 0
+    Decr {
+        /// The key to decrement
+        key: String,
+        /// The amount to subtract
+        #[arg(long = "by", default_value_t = 1)]
+        by: i64,
+    },
+    /// Open the .env file in $EDITOR, only saving the result if it still parses
+    Edit,
+    /// Print a shell completion script
+    Completions {
+        /// The shell to generate completions for
+        shell: clap_complete::Shell,
+    },
+    /// Print a man page in roff format
+    Man,
+    /// Run a command with the environment variables from the .env file
+    Run {
+        /// Start from an empty environment instead of inheriting the current one
+        #[arg(long = "no-inherit")]
+        no_inherit: bool,
+        /// Only pass keys starting with this prefix, e.g. APP_, to the command
+        #[arg(long = "env-prefix", value_name = "PREFIX")]
+        env_prefix: Option<String>,
+        /// Remove the --env-prefix prefix from the keys passed to the command, so
+        /// APP_PORT is passed as PORT
+        #[arg(long = "prefix-strip", requires = "env_prefix")]
+        prefix_strip: bool,
+        /// The command to run, followed by its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Print a template file with its ${KEY} placeholders filled in from the .env file
+    Template {
+        /// The template file to render
+        template: String,
+        /// Leave placeholders without a value as written instead of failing
+        #[arg(long = "allow-missing")]
+        allow_missing: bool,
+    },
+}
+
+/// Reads `file` as it was at git revision `rev` with `git show`, exiting with an
+/// error naming `flag` if that isn't possible.
+fn read_at_git_rev(file: &str, rev: &str, flag: &str) -> Vec<u8> {
+    if file == STDIN_PATH {
+        eprintln!("{} can't read from --file {}", flag, STDIN_PATH);
+        process::exit(EXIT_ERROR);
+    }
+
+    // Run git next to the file so paths found with --search-parents work too
+    let path = Path::new(file);
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let output = process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .arg("show")
+        .arg(format!("{}:./{}", rev, name))
+        .output()
+        .unwrap_or_else(|e| {
+            eprintln!("Error running git: {}", e);
+            process::exit(EXIT_ERROR);
+        });
+    if !output.status.success() {
+        eprint!("{}", String::from_utf8_lossy(&output.stderr));
+        process::exit(EXIT_ERROR);
+    }
+    output.stdout
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+    colored::control::set_override(cli.use_color());
+
+    if cli.search_parents && cli.file != STDIN_PATH && !Path::new(&cli.file).exists() {
+        let cwd = std::env::current_dir().unwrap_or_default();
+        if let Some(found) = find_in_parents(&cli.file, &cwd) {
+            eprintln!("Using {}", found.display());
+            cli.file = found.to_string_lossy().into_owned();
+        }
+    }
+
+    if cli.print_path {
+        if cli.file == STDIN_PATH {
+            println!("{}", STDIN_PATH);
+        } else {
+            match std::path::absolute(&cli.file) {
+                Ok(path) => println!("{}", path.display()),
+                Err(e) => {
+                    eprintln!("Error resolving {}: {}", cli.file, e);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+        }
+        return;
+    }
+
+    if cli.file == STDIN_PATH && cli.writes_env_file() {
+        eprintln!(
+            "Cannot write to stdin: --file {} only works with read-only commands",
+            STDIN_PATH
+        );
+        process::exit(EXIT_ERROR);
+    }
+
+    if let Some(rev) = &cli.git_rev {
+        if cli.writes_env_file() {
+            eprintln!("--git-rev only works with read-only commands");
+            process::exit(EXIT_ERROR);
+        }
+        let content = read_at_git_rev(&cli.file, rev, "--git-rev");
+        cli.git_content = Some(String::from_utf8_lossy(&content).into_owned());
+    }
 
     let mut should_print = cli.command.is_none() && cli.vars.is_empty();
 
     match &cli.command {
-        Some(Commands::Get { key }) => match read_env_vars(&cli.file) {
-            Ok(env_vars) => match env_vars.get(key) {
-                Some(value) => println!("{}", value),
-                None => {
-                    eprintln!("Environment variable '{}' not found", key);
-                    process::exit(1);
+        Some(Commands::Get {
+            keys,
+            values_only,
+            keep_missing,
+            json,
+            fail_on_empty,
+            raw,
+            best_effort,
+            overlays,
+        }) => {
+            let env_vars = if *raw {
+                read_raw_env_vars_with_overlays(cli.source(), overlays, cli.parse_options())
+            } else if *best_effort {
+                let mut env_vars =
+                    lines_to_map(&read_lines_best_effort(cli.source(), cli.parse_options()));
+                overlays
+                    .iter()
+                    .try_for_each(|overlay| {
+                        env_vars.extend(read_env_vars_ordered(overlay, cli.parse_options())?);
+                        Ok(())
+                    })
+                    .map(|()| env_vars)
+            } else {
+                read_env_vars_with_overlays(cli.source(), overlays, cli.parse_options())
+            };
+            match env_vars {
+                Ok(env_vars) => {
+                    let mut missing = false;
+                    if *json {
+                        if let Err(e) =
+                            print_values_as_json(&env_vars, keys, &mut std::io::stdout())
+                        {
+                            eprintln!("Error writing output: {}", e);
+                            process::exit(EXIT_ERROR);
+                        }
+                    }
+                    for key in keys {
+                        match env_vars.get(key) {
+                            Some(_) if *json => {}
+                            Some(value) if *values_only || keys.len() == 1 => println!("{}", value),
+                            Some(value) => println!("{}={}", key, value),
+                            None => {
+                                eprintln!("Environment variable '{}' not found", key);
+                                missing = true;
+                                if *keep_missing {
+                                    println!();
+                                }
+                            }
+                        }
+                        if *fail_on_empty && env_vars.get(key).is_some_and(String::is_empty) {
+                            eprintln!("Environment variable '{}' is empty", key);
+                            missing = true;
+                        }
+                    }
+                    if missing {
+                        process::exit(EXIT_NOT_FOUND);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error reading .env file: {}", e);
+                    process::exit(EXIT_ERROR);
                 }
-            },
-            Err(e) => {
-                eprintln!("Error reading .env file: {}", e);
-                process::exit(1);
             }
-        },
-        Some(Commands::Print { parse_tree, json }) => {
-            let use_color = atty::is(Stream::Stdout);
-            if *parse_tree {
-                print_parse_tree(&cli.file, &mut std::io::stdout());
-            } else if *json {
-                print_env_vars_as_json(&cli.file, &mut std::io::stdout());
+        }
+        Some(Commands::Print {
+            parse_tree,
+            json,
+            with_comments,
+            sort_keys,
+            format,
+            sort,
+            sort_by,
+            expand,
+            strict_expand,
+            only,
+            strict,
+            overlays,
+            mask,
+            mask_keys,
+            mask_reveal,
+            prefix_strip,
+            only_matching,
+            best_effort,
+            canonical,
+            files,
+            filter,
+        }) => {
+            let key_filter = filter.key_filter();
+            let mask = *mask || !mask_keys.is_empty() || mask_reveal.is_some();
+            let json = *json || *format == PrintFormat::Json;
+            if *with_comments && (*parse_tree || !json) {
+                eprintln!("--with-comments only works with the JSON output");
+                process::exit(EXIT_ERROR);
+            }
+            if *sort_keys && (*parse_tree || !json) {
+                eprintln!("--sort-keys only works with the JSON output");
+                process::exit(EXIT_ERROR);
+            }
+            let strip_prefix = prefix_strip.clone().map(|prefix| PrefixStrip {
+                prefix,
+                only_matching: *only_matching,
+            });
+            if strip_prefix.is_some()
+                && (*parse_tree
+                    || json
+                    || !matches!(format, PrintFormat::Dotenv | PrintFormat::Export))
+            {
+                eprintln!("--prefix-strip only works with the dotenv and export output");
+                process::exit(EXIT_ERROR);
+            }
+            if *parse_tree || json || *format != PrintFormat::Dotenv {
+                if !overlays.is_empty() {
+                    eprintln!("--overlay only works with the dotenv output");
+                    process::exit(EXIT_ERROR);
+                }
+                if mask {
+                    eprintln!("--mask only works with the dotenv output");
+                    process::exit(EXIT_ERROR);
+                }
+                if *best_effort {
+                    eprintln!("--best-effort only works with the dotenv output");
+                    process::exit(EXIT_ERROR);
+                }
+                if !only.is_empty() {
+                    eprintln!("--only only works with the dotenv output");
+                    process::exit(EXIT_ERROR);
+                }
+                if *sort || sort_by.is_some() {
+                    eprintln!("--sort only works with the dotenv output");
+                    process::exit(EXIT_ERROR);
+                }
+                if *expand {
+                    eprintln!("--expand only works with the dotenv output");
+                    process::exit(EXIT_ERROR);
+                }
+                if !files.is_empty() {
+                    eprintln!("Printing several files only works with the dotenv output");
+                    process::exit(EXIT_ERROR);
+                }
+            }
+            let result = if *parse_tree {
+                print_parse_tree(cli.source(), &mut std::io::stdout(), cli.parse_options())
+            } else if *canonical {
+                print_env_vars_canonical(
+                    cli.source(),
+                    &mut std::io::stdout(),
+                    &key_filter,
+                    cli.parse_options(),
+                )
+            } else if json && *with_comments {
+                print_env_vars_as_json_with_comments(
+                    cli.source(),
+                    &mut std::io::stdout(),
+                    &key_filter,
+                    cli.parse_options(),
+                )
+            } else if json {
+                print_env_vars_as_json(
+                    cli.source(),
+                    &mut std::io::stdout(),
+                    &key_filter,
+                    *sort_keys,
+                    cli.parse_options(),
+                )
+            } else if *format == PrintFormat::Yaml {
+                print_env_vars_as_yaml(
+                    cli.source(),
+                    &mut std::io::stdout(),
+                    &key_filter,
+                    cli.parse_options(),
+                )
+            } else if *format == PrintFormat::Toml {
+                print_env_vars_as_toml(
+                    cli.source(),
+                    &mut std::io::stdout(),
+                    &key_filter,
+                    cli.parse_options(),
+                )
+            } else if *format == PrintFormat::Export {
+                print_env_vars_as_export(
+                    cli.source(),
+                    &mut std::io::stdout(),
+                    &key_filter,
+                    strip_prefix.as_ref(),
+                    cli.parse_options(),
+                )
             } else {
-                print_env_vars(&cli.file, &mut std::io::stdout(), use_color);
+                let options = PrintOptions {
+                    use_color: cli.use_color(),
+                    quote_style: cli.quote_style,
+                    shell_safe: cli.shell_safe,
+                    filter: key_filter,
+                    sort: *sort || sort_by.is_some(),
+                    sort_by: sort_by.unwrap_or_default(),
+                    expand: *expand,
+                    strict_expand: *strict_expand,
+                    only: only.clone(),
+                    overlays: overlays.clone(),
+                    mask: mask.then(|| mask_reveal.unwrap_or(0)),
+                    mask_keys: mask_keys.clone(),
+                    strip_prefix,
+                    parse_options: cli.parse_options(),
+                };
+                let print_file = |source: EnvSource| {
+                    if *best_effort {
+                        let lines = read_lines_best_effort(source, cli.parse_options());
+                        print_env_lines(lines, &mut std::io::stdout(), &options)
+                    } else {
+                        print_env_vars(source, &mut std::io::stdout(), &options)
+                    }
+                };
+                if files.is_empty() {
+                    print_file(cli.source())
+                } else {
+                    // Keys missing from one file don't stop the others from printing
+                    let mut missing = Vec::new();
+                    for (index, path) in files.iter().enumerate() {
+                        if index > 0 {
+                            println!();
+                        }
+                        let header = format!("# === {} ===", path);
+                        if options.use_color {
+                            println!("{}", header.bright_black());
+                        } else {
+                            println!("{}", header);
+                        }
+                        match print_file(EnvSource::Path(path)) {
+                            Ok(()) => {}
+                            Err(EnvsetError::MissingKeys(keys)) => missing
+                                .extend(keys.into_iter().map(|key| format!("{} in {}", key, path))),
+                            Err(e) => {
+                                eprintln!("Error reading {}: {}", path, e);
+                                process::exit(EXIT_ERROR);
+                            }
+                        }
+                    }
+                    if missing.is_empty() {
+                        Ok(())
+                    } else {
+                        Err(EnvsetError::MissingKeys(missing))
+                    }
+                }
+            };
+            match result {
+                Ok(()) => {}
+                Err(EnvsetError::MissingKeys(keys)) => {
+                    eprintln!("Not found: {}", keys.join(", "));
+                    if *strict {
+                        process::exit(EXIT_NOT_FOUND);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error reading .env file: {}", e);
+                    process::exit(EXIT_ERROR);
+                }
             }
             return; // Exit after printing
         }
-        Some(Commands::Keys) => {
-            print_env_keys_to_writer(&cli.file, &mut std::io::stdout());
+        Some(Commands::Keys {
+            json,
+            missing: Some(example),
+            filter,
+        }) => {
+            let example_lines = read_lines(EnvSource::Path(example), example, cli.parse_options());
+            let content = read_existing_env_file(&cli);
+            let lines = parse_lines(&content, cli.parse_options()).unwrap_or_else(|e| {
+                eprintln!("Error reading {}: {}", cli.file, e);
+                process::exit(EXIT_ERROR);
+            });
+            let missing = print_missing_keys(
+                &example_lines,
+                &lines,
+                &mut std::io::stdout(),
+                &filter.key_filter(),
+                *json,
+            )
+            .unwrap_or_else(|e| {
+                eprintln!("Error writing keys: {}", e);
+                process::exit(EXIT_ERROR);
+            });
+            if missing {
+                process::exit(EXIT_NOT_FOUND);
+            }
+            return;
         }
-        Some(Commands::Delete { keys }) => match read_env_file_contents(&cli.file) {
-            Ok(old_content) => match envset::delete_env_vars(&old_content, keys) {
-                Ok(updated_lines) => {
-                    let mut buffer = Vec::new();
-                    if let Err(e) = print_env_file_contents(&updated_lines, &mut buffer) {
-                        eprintln!("Error writing .env file contents: {}", e);
-                        process::exit(1);
+        Some(Commands::Keys { json, filter, .. }) => {
+            let result = if *json {
+                print_env_keys_as_json(
+                    cli.source(),
+                    &mut std::io::stdout(),
+                    &filter.key_filter(),
+                    cli.parse_options(),
+                )
+            } else {
+                print_env_keys_to_writer(
+                    cli.source(),
+                    &mut std::io::stdout(),
+                    &filter.key_filter(),
+                    cli.parse_options(),
+                )
+            };
+            if let Err(e) = result {
+                eprintln!("Error reading .env file: {}", e);
+                process::exit(EXIT_ERROR);
+            }
+        }
+        Some(Commands::Count { with_comments }) => {
+            match count_env_file(cli.source(), cli.parse_options()) {
+                Ok(counts) => {
+                    if *with_comments {
+                        println!("keys: {}", counts.keys);
+                        println!("comments: {}", counts.comments);
+                        println!("empty lines: {}", counts.empty_lines);
+                    } else {
+                        println!("{}", counts.keys);
                     }
-                    let new_content = String::from_utf8_lossy(&buffer);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Error reading .env file: {}", e);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+        }
+        Some(Commands::Delete {
+            keys,
+            ignore_missing,
+            empty,
+            tombstone,
+        }) => {
+            let keys = if *empty {
+                Vec::new()
+            } else if keys.is_empty() && !atty::is(Stream::Stdin) {
+                parse_keys_with_reader(&mut std::io::stdin()).unwrap_or_else(|e| {
+                    eprintln!("Error reading keys from stdin: {}", e);
+                    process::exit(EXIT_ERROR);
+                })
+            } else {
+                keys.clone()
+            };
+            if keys.is_empty() && !*empty {
+                eprintln!("No keys to delete. Pass them as arguments or one per line on stdin");
+                process::exit(EXIT_ERROR);
+            }
+            match read_env_file_contents(&cli.file) {
+                Ok(old_content) => match if *empty {
+                    delete_empty_env_vars(&old_content, cli.parse_options())
+                } else {
+                    delete_env_vars(&old_content, &keys, *tombstone, cli.parse_options())
+                } {
+                    Ok((updated_lines, deleted)) => {
+                        let missing: Vec<&str> = keys
+                            .iter()
+                            .filter(|key| !deleted.contains(key))
+                            .map(String::as_str)
+                            .collect();
+                        if !deleted.is_empty() {
+                            eprintln!("Deleted: {}", deleted.join(", "));
+                        }
+                        if !missing.is_empty() && !*ignore_missing {
+                            eprintln!("Not found: {}", missing.join(", "));
+                        }
+                        if deleted.is_empty() {
+                            if *empty {
+                                eprintln!("No empty values found to delete");
+                            }
+                            process::exit(if *ignore_missing || *empty {
+                                0
+                            } else {
+                                EXIT_NOT_FOUND
+                            });
+                        }
+
+                        let mut buffer = Vec::new();
+                        if let Err(e) = print_env_file_contents(
+                            &updated_lines,
+                            &mut buffer,
+                            cli.quote_style,
+                            cli.shell_safe,
+                            cli.comment_char,
+                        ) {
+                            eprintln!("Error writing .env file contents: {}", e);
+                            process::exit(EXIT_ERROR);
+                        }
 
-                    if old_content == new_content {
+                        write_env_file(&cli, &old_content, buffer);
+                    }
+                    Err(e) => {
+                        eprintln!("Error deleting environment variables: {}", e);
+                        process::exit(EXIT_ERROR);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Error reading .env file: {}", e);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+        }
+        Some(Commands::Fmt {
+            prune,
+            dedupe,
+            check,
+            no_sort,
+            group,
+            normalize_keys,
+            ignore_case,
+            sort_by,
+            align,
+        }) => match cli.source().read() {
+            Ok(old_content) => match envset::format_env_file(
+                &old_content,
+                &FormatOptions {
+                    prune: *prune,
+                    dedupe: *dedupe,
+                    no_sort: *no_sort,
+                    ignore_case: *ignore_case,
+                    sort_by: *sort_by,
+                    group: *group,
+                    normalize_keys: *normalize_keys,
+                    parse_options: cli.parse_options(),
+                },
+            ) {
+                Ok(formatted_lines) => {
+                    if formatted_lines.is_empty() && !old_content.trim().is_empty() {
                         eprintln!(
-                            "No environment variables found to delete. Attempted to delete: {}",
-                            keys.join(", ")
+                            "Not formatting {}: every line would be dropped, leaving it empty",
+                            cli.file
                         );
-                        process::exit(1);
+                        process::exit(EXIT_ERROR);
+                    }
+                    let mut buffer = Vec::new();
+                    let printed = if *align {
+                        print_aligned_env_file_contents(
+                            &formatted_lines,
+                            &mut buffer,
+                            cli.quote_style,
+                            cli.shell_safe,
+                            cli.comment_char,
+                        )
+                    } else {
+                        print_env_file_contents(
+                            &formatted_lines,
+                            &mut buffer,
+                            cli.quote_style,
+                            cli.shell_safe,
+                            cli.comment_char,
+                        )
+                    };
+                    if let Err(e) = printed {
+                        eprintln!("Error writing formatted .env file contents: {}", e);
+                        process::exit(EXIT_ERROR);
                     }
 
-                    let use_color = atty::is(Stream::Stdout);
-                    print_diff(&old_content, &new_content, use_color);
-
-                    if let Err(e) = std::fs::write(&cli.file, buffer) {
-                        eprintln!("Error writing .env file: {}", e);
-                        process::exit(1);
+                    if *check {
+                        let new_content = finish_output(&cli, &old_content, &buffer);
+                        if new_content != old_content {
+                            let _ = print_diff(
+                                &mut std::io::stdout(),
+                                &old_content,
+                                &new_content,
+                                cli.use_color(),
+                            );
+                            eprintln!("{} is not formatted", cli.file);
+                            process::exit(EXIT_NOT_FOUND);
+                        }
+                        return;
                     }
+                    write_env_file(&cli, &old_content, buffer);
                 }
                 Err(e) => {
-                    eprintln!("Error deleting environment variables: {}", e);
-                    process::exit(1);
+                    eprintln!("Error formatting .env file: {}", e);
+                    process::exit(EXIT_ERROR);
                 }
             },
             Err(e) => {
                 eprintln!("Error reading .env file: {}", e);
-                process::exit(1);
+                process::exit(EXIT_ERROR);
             }
         },
-        Some(Commands::Fmt { prune }) => match read_env_file_contents(&cli.file) {
-            Ok(old_content) => match envset::format_env_file(&old_content, *prune) {
-                Ok(formatted_lines) => {
-                    let mut buffer = Vec::new();
-                    if let Err(e) = print_env_file_contents(&formatted_lines, &mut buffer) {
-                        eprintln!("Error writing formatted .env file contents: {}", e);
-                        process::exit(1);
-                    }
-                    let new_content = String::from_utf8_lossy(&buffer);
-
-                    let use_color = atty::is(Stream::Stdout);
-                    print_diff(&old_content, &new_content, use_color);
+        Some(Commands::Sort {
+            ignore_case,
+            sort_by,
+        }) => match read_env_file_contents(&cli.file) {
+            Ok(old_content) => {
+                match sort_env_file(&old_content, *ignore_case, *sort_by, cli.parse_options()) {
+                    Ok(sorted_lines) => {
+                        let mut buffer = Vec::new();
+                        if let Err(e) = print_env_file_contents(
+                            &sorted_lines,
+                            &mut buffer,
+                            cli.quote_style,
+                            cli.shell_safe,
+                            cli.comment_char,
+                        ) {
+                            eprintln!("Error writing sorted .env file contents: {}", e);
+                            process::exit(EXIT_ERROR);
+                        }
 
-                    if let Err(e) = std::fs::write(&cli.file, buffer) {
-                        eprintln!("Error writing formatted .env file: {}", e);
-                        process::exit(1);
+                        write_env_file(&cli, &old_content, buffer);
+                    }
+                    Err(e) => {
+                        eprintln!("Error sorting .env file: {}", e);
+                        process::exit(EXIT_ERROR);
                     }
                 }
-                Err(e) => {
-                    eprintln!("Error formatting .env file: {}", e);
-                    process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Error reading .env file: {}", e);
+                process::exit(EXIT_ERROR);
+            }
+        },
+        Some(Commands::Validate { warn_suspicious }) => match cli.source().read() {
+            Ok(content) => {
+                if let Err(errors) = validate_env_content(&content, cli.parse_options()) {
+                    for error in errors {
+                        eprintln!("{}: {}", cli.file, error);
+                    }
+                    process::exit(EXIT_ERROR);
                 }
-            },
+                if *warn_suspicious {
+                    let warnings = find_suspicious_values(&content, cli.parse_options())
+                        .unwrap_or_else(|e| {
+                            eprintln!("{}: {}", cli.file, e);
+                            process::exit(EXIT_ERROR);
+                        });
+                    for warning in warnings {
+                        eprintln!("{}: warning: {}", cli.file, warning);
+                    }
+                }
+                return;
+            }
             Err(e) => {
                 eprintln!("Error reading .env file: {}", e);
-                process::exit(1);
+                process::exit(EXIT_ERROR);
             }
         },
+        Some(Commands::Lint { deny }) => match cli.source().read() {
+            Ok(content) => {
+                if let Err(errors) = validate_env_content(&content, cli.parse_options()) {
+                    for error in errors {
+                        eprintln!("{}: {}", cli.file, error);
+                    }
+                    process::exit(EXIT_ERROR);
+                }
+                let mut denied = false;
+                let findings =
+                    lint_env_content(&content, cli.parse_options()).unwrap_or_else(|e| {
+                        eprintln!("{}: {}", cli.file, e);
+                        process::exit(EXIT_ERROR);
+                    });
+                for finding in findings {
+                    let level = if deny.contains(&finding.rule) {
+                        denied = true;
+                        "error"
+                    } else {
+                        "warning"
+                    };
+                    eprintln!(
+                        "{}:{}: {}: {} [{}]",
+                        cli.file, finding.line, level, finding.message, finding.rule
+                    );
+                }
+                if denied {
+                    process::exit(EXIT_NOT_FOUND);
+                }
+                return;
+            }
+            Err(e) => {
+                eprintln!("Error reading .env file: {}", e);
+                process::exit(EXIT_ERROR);
+            }
+        },
+        Some(Commands::Diff {
+            other,
+            since,
+            exit_zero,
+        }) => {
+            let (old_lines, new_lines) = match (since, other) {
+                (Some(rev), _) => {
+                    let content = read_at_git_rev(&cli.file, rev, "--since");
+                    let content = String::from_utf8_lossy(&content);
+                    let old_lines =
+                        parse_lines(&content, cli.parse_options()).unwrap_or_else(|e| {
+                            eprintln!("Error reading {} at {}: {}", cli.file, rev, e);
+                            process::exit(EXIT_ERROR);
+                        });
+                    (
+                        old_lines,
+                        read_lines(cli.source(), &cli.file, cli.parse_options()),
+                    )
+                }
+                (None, Some(other)) => (
+                    read_lines(cli.source(), &cli.file, cli.parse_options()),
+                    read_lines(EnvSource::Path(other), other, cli.parse_options()),
+                ),
+                (None, None) => unreachable!("clap requires the other file without --since"),
+            };
+
+            let diff = diff_envs(&old_lines, &new_lines);
+            let differs = print_env_diff(&diff, cli.use_color());
+            if differs && !*exit_zero {
+                process::exit(EXIT_NOT_FOUND);
+            }
+            return;
+        }
+        Some(Commands::CheckExample { example, sync }) => {
+            let example_lines = read_lines(EnvSource::Path(example), example, cli.parse_options());
+            let old_content = read_existing_env_file(&cli);
+            let lines = parse_lines(&old_content, cli.parse_options()).unwrap_or_else(|e| {
+                eprintln!("Error reading {}: {}", cli.file, e);
+                process::exit(EXIT_ERROR);
+            });
+
+            let check = check_against_example(&example_lines, &lines);
+            if *sync && !check.missing.is_empty() {
+                let missing: Vec<(String, String)> = check
+                    .missing
+                    .iter()
+                    .map(|key| (key.clone(), String::new()))
+                    .collect();
+                let options = MergeOptions {
+                    no_overwrite: true,
+                    parse_options: cli.parse_options(),
+                    ..Default::default()
+                };
+                match merge_env_vars(&old_content, &missing, &options) {
+                    Ok(synced_lines) => {
+                        let mut buffer = Vec::new();
+                        if let Err(e) = print_env_file_contents(
+                            &synced_lines,
+                            &mut buffer,
+                            cli.quote_style,
+                            cli.shell_safe,
+                            cli.comment_char,
+                        ) {
+                            eprintln!("Error writing .env file contents: {}", e);
+                            process::exit(EXIT_ERROR);
+                        }
+                        write_env_file(&cli, &old_content, buffer);
+                    }
+                    Err(e) => {
+                        eprintln!("Error updating .env file contents: {}", e);
+                        process::exit(EXIT_ERROR);
+                    }
+                }
+            } else if !check.missing.is_empty() {
+                println!("Missing from {}:", cli.file);
+                for key in &check.missing {
+                    println!("  {}", key);
+                }
+            }
+            if !check.extra.is_empty() {
+                println!("Not in {}:", example);
+                for key in &check.extra {
+                    println!("  {}", key);
+                }
+            }
+            if !*sync && !check.missing.is_empty() {
+                process::exit(EXIT_NOT_FOUND);
+            }
+            return;
+        }
+        Some(Commands::Merge { overlay, only_new }) => {
+            let overlay_vars =
+                read_env_vars_ordered(overlay, cli.parse_options()).unwrap_or_else(|e| {
+                    eprintln!("Error reading {}: {}", overlay, e);
+                    process::exit(EXIT_ERROR);
+                });
+            let old_content = read_existing_env_file(&cli);
+
+            let options = MergeOptions {
+                no_overwrite: *only_new,
+                parse_options: cli.parse_options(),
+                ..Default::default()
+            };
+            match merge_env_vars(&old_content, &overlay_vars, &options) {
+                Ok(merged_lines) => {
+                    let mut buffer = Vec::new();
+                    if let Err(e) = print_env_file_contents(
+                        &merged_lines,
+                        &mut buffer,
+                        cli.quote_style,
+                        cli.shell_safe,
+                        cli.comment_char,
+                    ) {
+                        eprintln!("Error writing .env file contents: {}", e);
+                        process::exit(EXIT_ERROR);
+                    }
+
+                    write_env_file(&cli, &old_content, buffer);
+                }
+                Err(e) => {
+                    eprintln!("Error merging .env files: {}", e);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+            return;
+        }
+        Some(Commands::Completions { shell }) => {
+            print_completions(*shell, &mut std::io::stdout());
+            return;
+        }
+        Some(Commands::Man) => {
+            if let Err(e) = print_man_page(&mut std::io::stdout()) {
+                eprintln!("Error writing man page: {}", e);
+                process::exit(EXIT_ERROR);
+            }
+            return;
+        }
+        Some(Commands::Incr { key, by }) | Some(Commands::Decr { key, by }) => {
+            let by = match &cli.command {
+                Some(Commands::Decr { .. }) => by.checked_neg().unwrap_or_else(|| {
+                    eprintln!("--by {} is out of range", by);
+                    process::exit(EXIT_ERROR);
+                }),
+                _ => *by,
+            };
+            let old_content = read_existing_env_file(&cli);
+            match increment_env_var(&old_content, key, by, cli.parse_options()) {
+                Ok(lines) => {
+                    let mut buffer = Vec::new();
+                    if let Err(e) = print_env_file_contents(
+                        &lines,
+                        &mut buffer,
+                        cli.quote_style,
+                        cli.shell_safe,
+                        cli.comment_char,
+                    ) {
+                        eprintln!("Error writing .env file contents: {}", e);
+                        process::exit(EXIT_ERROR);
+                    }
+                    write_env_file(&cli, &old_content, buffer);
+                }
+                Err(e) => {
+                    eprintln!("Error updating {}: {}", key, e);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+            return;
+        }
+        Some(Commands::Edit) => {
+            let old_content = read_existing_env_file(&cli);
+            let new_content =
+                edit_in_editor(&old_content, cli.parse_options()).unwrap_or_else(|e| {
+                    eprintln!("Error editing .env file: {}", e);
+                    process::exit(EXIT_ERROR);
+                });
+            write_env_file(&cli, &old_content, new_content.into_bytes());
+            return;
+        }
+        Some(Commands::Run {
+            no_inherit,
+            env_prefix,
+            prefix_strip,
+            command,
+        }) => {
+            let mut env_vars = read_env_vars_ordered(cli.source(), cli.parse_options())
+                .unwrap_or_else(|e| {
+                    eprintln!("Error reading .env file: {}", e);
+                    process::exit(EXIT_ERROR);
+                });
+            if let Some(prefix) = env_prefix {
+                let strip = prefix_strip.then(|| PrefixStrip {
+                    prefix: prefix.clone(),
+                    only_matching: true,
+                });
+                env_vars = env_vars
+                    .into_iter()
+                    .filter(|(key, _)| key.starts_with(prefix.as_str()))
+                    .filter_map(|(key, value)| match &strip {
+                        Some(strip) => strip.apply(&key).map(|key| (key, value)),
+                        None => Some((key, value)),
+                    })
+                    .collect();
+            }
+
+            let mut child = process::Command::new(&command[0]);
+            child.args(&command[1..]);
+            if *no_inherit {
+                child.env_clear();
+            }
+            child.envs(env_vars);
+
+            match child.status() {
+                Ok(status) => process::exit(status.code().unwrap_or(EXIT_ERROR)),
+                Err(e) => {
+                    eprintln!("Error running {}: {}", command[0], e);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+        }
+        Some(Commands::Template {
+            template,
+            allow_missing,
+        }) => {
+            let env_vars: HashMap<_, _> = read_env_vars_ordered(cli.source(), cli.parse_options())
+                .unwrap_or_else(|e| {
+                    eprintln!("Error reading .env file: {}", e);
+                    process::exit(EXIT_ERROR);
+                })
+                .into_iter()
+                .collect();
+            let content = std::fs::read_to_string(template).unwrap_or_else(|e| {
+                eprintln!("Error reading {}: {}", template, e);
+                process::exit(EXIT_ERROR);
+            });
+            match render_template(&content, &env_vars, *allow_missing) {
+                Ok(rendered) => print!("{}", rendered),
+                Err(e) => {
+                    eprintln!("Error rendering {}: {}", template, e);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+            return;
+        }
         None => {}
     }
 
-    let new_vars = if !atty::is(Stream::Stdin) || !cli.vars.is_empty() {
-        if !atty::is(Stream::Stdin) {
-            parse_stdin()
+    // With `--file -` stdin holds the env file itself rather than vars to set
+    let stdin_vars = cli.file != STDIN_PATH && !atty::is(Stream::Stdin);
+    let stdin_format = if cli.from_json {
+        StdinFormat::Json
+    } else {
+        cli.stdin_format
+    };
+
+    if cli.replace {
+        if !stdin_vars && cli.vars.is_empty() {
+            eprintln!("--replace needs the new contents on stdin or as KEY=value arguments");
+            process::exit(EXIT_ERROR);
+        }
+        let new_lines = if stdin_vars && stdin_format == StdinFormat::Dotenv {
+            read_env_file_contents(STDIN_PATH)
+                .map_err(EnvsetError::from)
+                .and_then(|content| parse_lines(&content, cli.parse_options()))
+                .unwrap_or_else(|e| {
+                    eprintln!("Error parsing stdin: {}", e);
+                    process::exit(EXIT_ERROR);
+                })
+        } else {
+            let vars = if stdin_vars {
+                parse_stdin(stdin_format, cli.parse_options()).map_err(|e| e.to_string())
+            } else {
+                parse_args(&cli.vars)
+            };
+            let mut vars: Vec<(String, String)> = vars
+                .unwrap_or_else(|e| {
+                    eprintln!("Error parsing vars: {}", e);
+                    process::exit(EXIT_ERROR);
+                })
+                .into_iter()
+                .collect();
+            vars.sort();
+            let options = MergeOptions {
+                export: cli.export,
+                comment: cli.comment.clone(),
+                parse_options: cli.parse_options(),
+                ..Default::default()
+            };
+            merge_env_vars("", &vars, &options).unwrap_or_else(|e| {
+                eprintln!("Error updating .env file contents: {}", e);
+                process::exit(EXIT_ERROR);
+            })
+        };
+
+        let old_content = read_existing_env_file(&cli);
+        let mut buffer = Vec::new();
+        if let Err(e) = print_env_file_contents(
+            &new_lines,
+            &mut buffer,
+            cli.quote_style,
+            cli.shell_safe,
+            cli.comment_char,
+        ) {
+            eprintln!("Error writing .env file contents: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+        let changed = write_env_file(&cli, &old_content, buffer);
+        if changed && cli.exit_changed {
+            process::exit(EXIT_CHANGED);
+        }
+        return;
+    }
+    let new_vars = if cli.interactive {
+        prompt_for_values(&cli.file, &cli.vars, cli.parse_options())
+    } else if stdin_vars || !cli.vars.is_empty() {
+        if stdin_vars {
+            parse_stdin(stdin_format, cli.parse_options()).unwrap_or_else(|e| {
+                eprintln!("Error parsing stdin: {}", e);
+                process::exit(EXIT_ERROR);
+            })
         } else {
             match parse_args(&cli.vars) {
                 Ok(vars) => vars,
                 Err(e) => {
                     eprintln!("Error parsing arguments: {}", e);
-                    process::exit(1);
+                    process::exit(EXIT_ERROR);
                 }
             }
         }
@@ -215,45 +1857,75 @@ fn main() {
 
     if !new_vars.is_empty() {
         should_print = false; // Don't print all vars when setting new ones
-        let mut env_vars = read_env_vars(&cli.file).unwrap_or_else(|e| {
+
+        // Vars piped in on stdin are only known once they've been read
+        if cli.git_rev.is_some() {
+            eprintln!("--git-rev only works with read-only commands");
+            process::exit(EXIT_ERROR);
+        }
+
+        // Also creates the file if it doesn't exist yet
+        let env_vars = read_env_vars(&cli.file, cli.parse_options()).unwrap_or_else(|e| {
             eprintln!("Error reading .env file: {}", e);
-            process::exit(1);
+            process::exit(EXIT_ERROR);
         });
 
-        env_vars.extend(new_vars);
+        let new_vars: Vec<(String, String)> = new_vars.into_iter().collect();
+        let new_vars = if cli.append {
+            append_env_vars(&env_vars, new_vars, &cli.separator)
+        } else {
+            new_vars
+        };
+        let options = MergeOptions {
+            no_overwrite: cli.no_overwrite,
+            export: cli.export,
+            comment: cli.comment.clone(),
+            parse_options: cli.parse_options(),
+        };
 
         match read_env_file_contents(&cli.file) {
-            Ok(old_content) => match add_env_vars(&old_content, &env_vars) {
+            Ok(old_content) => match merge_env_vars(&old_content, &new_vars, &options) {
                 Ok(updated_lines) => {
                     let mut buffer = Vec::new();
-                    if let Err(e) = print_env_file_contents(&updated_lines, &mut buffer) {
+                    if let Err(e) = print_env_file_contents(
+                        &updated_lines,
+                        &mut buffer,
+                        cli.quote_style,
+                        cli.shell_safe,
+                        cli.comment_char,
+                    ) {
                         eprintln!("Error writing .env file contents: {}", e);
-                        process::exit(1);
+                        process::exit(EXIT_ERROR);
                     }
-                    let new_content = String::from_utf8_lossy(&buffer);
-
-                    let use_color = atty::is(Stream::Stdout);
-                    print_diff(&old_content, &new_content, use_color);
 
-                    if let Err(e) = std::fs::write(&cli.file, buffer) {
-                        eprintln!("Error writing .env file: {}", e);
-                        process::exit(1);
+                    let changed = write_env_file(&cli, &old_content, buffer);
+                    if changed && cli.exit_changed {
+                        process::exit(EXIT_CHANGED);
                     }
                 }
                 Err(e) => {
                     eprintln!("Error updating .env file contents: {}", e);
-                    process::exit(1);
+                    process::exit(EXIT_ERROR);
                 }
             },
             Err(e) => {
                 eprintln!("Error reading .env file: {}", e);
-                process::exit(1);
+                process::exit(EXIT_ERROR);
             }
         }
     }
 
     if should_print {
-        let use_color = atty::is(Stream::Stdout);
-        print_env_vars(&cli.file, &mut std::io::stdout(), use_color);
+        let options = PrintOptions {
+            use_color: cli.use_color(),
+            quote_style: cli.quote_style,
+            shell_safe: cli.shell_safe,
+            parse_options: cli.parse_options(),
+            ..Default::default()
+        };
+        if let Err(e) = print_env_vars(cli.source(), &mut std::io::stdout(), &options) {
+            eprintln!("Error reading .env file: {}", e);
+            process::exit(EXIT_ERROR);
+        }
     }
 }
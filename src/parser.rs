@@ -2,84 +2,235 @@ use chumsky::prelude::*;
 
 use serde::Serialize;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub enum Line {
     Comment(String),
+    Blank,
     KeyValue {
         key: String,
         value: String,
         comment: Option<String>,
+        /// How the value was quoted in the source, `None` for values that were
+        /// set or changed and should be quoted by the output policy
+        quoting: Option<Quoting>,
+        /// Whether the line starts with `export `, as in shell-sourceable files
+        export: bool,
+        /// Whether the line starts with `set `, as in batch files. Only
+        /// recognized in the cmd dialect
+        set: bool,
+        /// The value as written in the source, between any quotes and before
+        /// unescaping, so an untouched line can be written back as it was.
+        /// Only filled in for lines read with `iter_lines`
+        #[serde(skip)]
+        raw_value: Option<String>,
     },
 }
 
+/// Which flavor of env file to parse.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Dialect {
+    /// Plain `KEY=value` lines, optionally prefixed with `export `
+    #[default]
+    Dotenv,
+    /// Also accept Windows batch style `set KEY=value` lines
+    Cmd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Quoting {
+    Unquoted,
+    Single,
+    Double,
+}
+
 // Parser for keys
 pub fn key_parser() -> impl Parser<char, String, Error = Simple<char>> + Clone {
-    text::ident().padded()
+    text::ident().padded().then_ignore(end())
+}
+
+// Parser for the entire file, with `comment_char` starting comments
+pub fn parser(
+    dialect: Dialect,
+    comment_char: char,
+) -> impl Parser<char, Vec<Line>, Error = Simple<char>> + Clone {
+    spanned_line_parser(dialect, comment_char)
+        .map(|(line, _)| line)
+        .repeated()
+        .then_ignore(end())
 }
 
-pub fn parser() -> impl Parser<char, Vec<Line>, Error = Simple<char>> + Clone {
+// Parser for a single line including its line ending, which is optional on the
+// last line. A double-quoted value may span several lines of text. Also returns
+// the span of a key-value line's value as written in the source, between the
+// quotes and before any unescaping
+pub fn spanned_line_parser(
+    dialect: Dialect,
+    comment_char: char,
+) -> impl Parser<char, (Line, Option<std::ops::Range<usize>>), Error = Simple<char>> + Clone {
+    // Parser for spaces and tabs within a line
+    let inline_whitespace = one_of(" \t").repeated();
+
+    // Parser for the rest of a line, up to but not including the newline
+    let rest_of_line = filter(|&c| c != '\n')
+        .repeated()
+        .collect::<String>()
+        .map(|s| s.trim_end_matches('\r').to_string());
+
     // Parser for comments
-    let comment = just('#')
-        .ignore_then(take_until(text::newline().or(end())))
-        .map(|(chars, _)| chars.into_iter().collect::<String>())
+    let comment = just(comment_char)
+        .ignore_then(rest_of_line)
         .map(Line::Comment);
 
-    let key = key_parser();
+    // Keys are only padded within the line so blank lines around them are kept
+    let key = text::ident().padded_by(inline_whitespace.clone());
+
+    // A keyword like `export` followed by at least one space before a key
+    let keyword_prefix = |keyword| {
+        inline_whitespace
+            .clone()
+            .ignore_then(text::keyword(keyword))
+            .then(one_of(" \t").repeated().at_least(1))
+    };
+
+    // Optional `export ` prefix before a key, or `set ` in the cmd dialect,
+    // parsed as whether each one was there
+    let prefix = match dialect {
+        Dialect::Dotenv => keyword_prefix("export").to((true, false)).boxed(),
+        Dialect::Cmd => keyword_prefix("export")
+            .to((true, false))
+            .or(keyword_prefix("set").to((false, true)))
+            .boxed(),
+    }
+    .or_not()
+    .map(Option::unwrap_or_default);
 
     // Parser for single-quoted values
     let single_quoted_value = just('\'')
-        .ignore_then(filter(|&c| c != '\'').repeated().collect::<String>())
+        .ignore_then(
+            filter(|&c| c != '\'')
+                .repeated()
+                .collect::<String>()
+                .map_with_span(|s, span| (s, span)),
+        )
         .then_ignore(just('\''));
 
-    // Parser for escape sequences in double-quoted values
-    let escape_sequence = just('\\').then(any());
+    // Parser for escape sequences in double-quoted values. Multi-line values are
+    // written with literal newlines, but `\n`, `\r` and `\t` escapes are decoded too.
+    let escape_sequence = just('\\').ignore_then(any()).map(|c| match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        c => c,
+    });
 
-    // Parser for double-quoted values
+    // Parser for double-quoted values. Each character remembers whether it was
+    // escaped, so an escaped trailing newline is kept when the value is trimmed.
     let double_quoted_value = just('"')
         .ignore_then(
             choice((
-                escape_sequence.map(|(_, c)| c),
-                filter(|&c| c != '"' && c != '\\'),
+                escape_sequence.map(|c| (c, true)),
+                filter(|&c| c != '"' && c != '\\').map(|c| (c, false)),
             ))
             .repeated()
-            .collect::<String>(),
+            .map(|chars: Vec<(char, bool)>| {
+                let literal_tail = chars
+                    .iter()
+                    .rev()
+                    .take_while(|&&(c, escaped)| !escaped && c.is_whitespace())
+                    .count();
+                let tail = &chars[chars.len() - literal_tail..];
+                let keep = if tail.iter().any(|&(c, _)| c == '\n') {
+                    chars.len() - literal_tail
+                } else {
+                    chars.len()
+                };
+                chars[..keep].iter().map(|&(c, _)| c).collect::<String>()
+            })
+            .map_with_span(|s, span| (s, span)),
         )
         .then_ignore(just('"'));
 
-    // Parser for unquoted values
+    // Parser for unquoted values. A `#` only starts a comment at the beginning of
+    // a word, so `a#b` is a single value while `a #b` is `a` with a comment.
     let unquoted_value = {
         let escape_sequence = just('\\').then(any()).map(|(_, c)| c);
-        let unescaped_char = filter(|&c| c != '#' && c != '\n' && c != '\\');
-        choice((escape_sequence, unescaped_char))
-            .repeated()
-            .collect::<String>()
+        let word_start =
+            filter(move |&c: &char| !c.is_whitespace() && c != comment_char && c != '\\');
+        let word_char = filter(|&c: &char| !c.is_whitespace() && c != '\\');
+        let word = choice((escape_sequence, word_start))
+            .chain::<char, Vec<char>, _>(choice((escape_sequence, word_char)).repeated());
+        let spacing = one_of(" \t").repeated().at_least(1);
+        word.chain::<char, Vec<char>, _>(
+            spacing
+                .chain::<char, Vec<char>, _>(word)
+                .repeated()
+                .flatten(),
+        )
+        .or_not()
+        .map(|chars| chars.unwrap_or_default().into_iter().collect::<String>())
+        .map_with_span(|s, span| (s, span))
     };
 
-    let value = choice((single_quoted_value, double_quoted_value, unquoted_value))
-        .map(|s| s.trim_end().to_string());
+    let value = choice((
+        single_quoted_value.map(|(s, span)| (s, span, Quoting::Single)),
+        double_quoted_value.map(|(s, span)| (s, span, Quoting::Double)),
+        unquoted_value.map(|(s, span)| (s, span, Quoting::Unquoted)),
+    ))
+    // Spaces inside quotes are part of the value, so a quoted value is only
+    // trimmed when its closing quote sits on a line of its own. Double-quoted
+    // values have already been trimmed above.
+    .map(|(s, span, quoting)| {
+        let trimmed = s.trim_end();
+        if quoting == Quoting::Unquoted
+            || (quoting == Quoting::Single && s[trimmed.len()..].contains('\n'))
+        {
+            (trimmed.to_string(), span, quoting)
+        } else {
+            (s, span, quoting)
+        }
+    });
 
     // Parser for trailing comments
-    let trailing_comment = just('#')
-        .ignore_then(take_until(text::newline().or(end())))
-        .map(|(chars, _)| chars.into_iter().collect::<String>())
-        .boxed();
+    let trailing_comment = just(comment_char).ignore_then(rest_of_line).boxed();
 
     // Parser for key-value lines
-    let key_value_line = key
+    let key_value_line = prefix
+        .then(key)
         .then_ignore(just('='))
-        .then(value.padded_by(just(' ').repeated()))
+        .then(value.padded_by(one_of(" \t").repeated()))
         .then(trailing_comment.or_not())
-        .map(|((key, value), comment)| Line::KeyValue {
-            key,
-            value,
-            comment,
-        });
+        .map(
+            |((((export, set), key), (value, span, quoting)), comment)| {
+                let line = Line::KeyValue {
+                    key,
+                    value,
+                    comment,
+                    quoting: Some(quoting),
+                    export,
+                    set,
+                    raw_value: None,
+                };
+                (line, Some(span))
+            },
+        );
 
     // Parser for a line (either a comment or a key-value pair)
-    let line = choice((comment, key_value_line));
+    let line = choice((
+        inline_whitespace
+            .clone()
+            .ignore_then(comment)
+            .map(|line| (line, None)),
+        key_value_line,
+    ));
+
+    let newline = just('\r').or_not().then(just('\n')).ignored();
 
-    // Parser for the entire file
-    line.padded_by(just('\n').repeated()).repeated()
+    // Parser for a blank line, which may contain only whitespace
+    let empty_line = inline_whitespace
+        .ignore_then(newline)
+        .to((Line::Blank, None));
+
+    choice((line.then_ignore(newline.or(end())), empty_line))
 }
 
 #[cfg(test)]
@@ -89,13 +240,14 @@ mod tests {
     #[test]
     fn test_simple_key_value_pair() {
         let input = "KEY=value\n";
-        let result = parser().parse(input).unwrap();
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
         assert_eq!(result.len(), 1);
         match &result[0] {
             Line::KeyValue {
                 key,
                 value,
                 comment,
+                ..
             } => {
                 assert_eq!(key, "KEY");
                 assert_eq!(value, "value");
@@ -108,10 +260,10 @@ mod tests {
     #[test]
     fn test_multiple_key_value_pairs() {
         let input = "KEY1=value1\nKEY2=value2\nKEY3=value3\n";
-        let result = parser().parse(input).unwrap();
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
         assert_eq!(result.len(), 3);
 
-        let expected = vec![("KEY1", "value1"), ("KEY2", "value2"), ("KEY3", "value3")];
+        let expected = [("KEY1", "value1"), ("KEY2", "value2"), ("KEY3", "value3")];
 
         for (i, (expected_key, expected_value)) in expected.iter().enumerate() {
             match &result[i] {
@@ -119,6 +271,7 @@ mod tests {
                     key,
                     value,
                     comment,
+                    ..
                 } => {
                     assert_eq!(key, expected_key);
                     assert_eq!(value, expected_value);
@@ -132,7 +285,7 @@ mod tests {
     #[test]
     fn test_whole_line_comment() {
         let input = "# This is a comment\n";
-        let result = parser().parse(input).unwrap();
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
         assert_eq!(result.len(), 1);
         match &result[0] {
             Line::Comment(comment) => {
@@ -145,13 +298,14 @@ mod tests {
     #[test]
     fn test_key_value_with_trailing_comment() {
         let input = "KEY=value # This is a trailing comment\n";
-        let result = parser().parse(input).unwrap();
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
         assert_eq!(result.len(), 1);
         match &result[0] {
             Line::KeyValue {
                 key,
                 value,
                 comment,
+                ..
             } => {
                 assert_eq!(key, "KEY");
                 assert_eq!(value, "value");
@@ -165,7 +319,7 @@ mod tests {
     fn test_env_var_with_mixed_comments() {
         let input =
             "# Comment before\nKEY1=value1\n# Comment in between\nKEY2=value2\n# Comment after\n";
-        let result = parser().parse(input).unwrap();
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
         assert_eq!(result.len(), 5);
 
         match &result[0] {
@@ -178,6 +332,7 @@ mod tests {
                 key,
                 value,
                 comment,
+                ..
             } => {
                 assert_eq!(key, "KEY1");
                 assert_eq!(value, "value1");
@@ -196,6 +351,7 @@ mod tests {
                 key,
                 value,
                 comment,
+                ..
             } => {
                 assert_eq!(key, "KEY2");
                 assert_eq!(value, "value2");
@@ -213,13 +369,14 @@ mod tests {
     #[test]
     fn test_value_with_trailing_whitespace() {
         let input = "KEY=value with space   \n";
-        let result = parser().parse(input).unwrap();
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
         assert_eq!(result.len(), 1);
         match &result[0] {
             Line::KeyValue {
                 key,
                 value,
                 comment,
+                ..
             } => {
                 assert_eq!(key, "KEY");
                 assert_eq!(value, "value with space");
@@ -229,6 +386,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_escaped_trailing_newline_is_kept() {
+        let result = parser(Dialect::Dotenv, '#')
+            .parse("KEY=\"a\nb\\n\"\nOTHER=\"c\n\"\n")
+            .unwrap();
+        let values: Vec<_> = result
+            .iter()
+            .filter_map(|line| match line {
+                Line::KeyValue { value, .. } => Some(value.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(values, vec!["a\nb\n", "c"]);
+    }
+
     #[test]
     fn test_multiline_quoted_value() {
         let input = r#"MULTILINE="
@@ -237,13 +409,14 @@ mod tests {
   lines
   # not a comment
 ""#;
-        let result = parser().parse(input).unwrap();
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
         assert_eq!(result.len(), 1);
         match &result[0] {
             Line::KeyValue {
                 key,
                 value,
                 comment,
+                ..
             } => {
                 assert_eq!(key, "MULTILINE");
                 assert_eq!(
@@ -265,13 +438,14 @@ mod tests {
   },
   "key3": [1, 2, 3]
 }'"#;
-        let result = parser().parse(input).unwrap();
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
         assert_eq!(result.len(), 1);
         match &result[0] {
             Line::KeyValue {
                 key,
                 value,
                 comment,
+                ..
             } => {
                 assert_eq!(key, "JSON_CONFIG");
                 assert_eq!(
@@ -289,4 +463,262 @@ mod tests {
             _ => panic!("Expected KeyValue, got {:?}", result[0]),
         }
     }
+
+    #[test]
+    fn test_invalid_line_is_an_error() {
+        let input = "KEY1=value1\n1INVALID=value\nKEY2=value2\n";
+        assert!(parser(Dialect::Dotenv, '#').parse(input).is_err());
+    }
+
+    #[test]
+    fn test_records_original_quoting() {
+        let input = "A=plain\nB='single'\nC=\"double\"\n";
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
+        let quotings: Vec<_> = result
+            .iter()
+            .map(|line| match line {
+                Line::KeyValue { quoting, .. } => *quoting,
+                _ => panic!("Expected KeyValue, got {:?}", line),
+            })
+            .collect();
+        assert_eq!(
+            quotings,
+            vec![
+                Some(Quoting::Unquoted),
+                Some(Quoting::Single),
+                Some(Quoting::Double)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_double_quoted_escaped_newlines() {
+        let input = r#"KEY="line1\nline2\ttabbed \"quoted\" \\ done""#;
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
+        match &result[0] {
+            Line::KeyValue { value, .. } => {
+                assert_eq!(value, "line1\nline2\ttabbed \"quoted\" \\ done");
+            }
+            _ => panic!("Expected KeyValue, got {:?}", result[0]),
+        }
+    }
+
+    fn single_value(input: &str) -> String {
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
+        assert_eq!(result.len(), 1);
+        match &result[0] {
+            Line::KeyValue { value, .. } => value.clone(),
+            _ => panic!("Expected KeyValue, got {:?}", result[0]),
+        }
+    }
+
+    #[test]
+    fn test_escape_sequences() {
+        assert_eq!(single_value("KEY=a\\#b\n"), "a#b");
+        assert_eq!(single_value("KEY=\"a\\$b\"\n"), "a$b");
+        assert_eq!(single_value("KEY=\"say \\\"hi\\\"\"\n"), "say \"hi\"");
+        assert_eq!(single_value("KEY=\"back\\\\slash\"\n"), "back\\slash");
+        // Single-quoted values are taken literally
+        assert_eq!(single_value("KEY='a\\nb'\n"), "a\\nb");
+    }
+
+    #[test]
+    fn test_spacing_around_equals() {
+        let result = parser(Dialect::Dotenv, '#')
+            .parse("KEY10  =value\nKEY11=   value\n")
+            .unwrap();
+        assert_eq!(result.len(), 2);
+        for (line, expected_key) in result.iter().zip(["KEY10", "KEY11"]) {
+            match line {
+                Line::KeyValue { key, value, .. } => {
+                    assert_eq!(key, expected_key);
+                    assert_eq!(value, "value");
+                }
+                _ => panic!("Expected KeyValue, got {:?}", line),
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_value() {
+        assert_eq!(single_value("KEY=\n"), "");
+        assert_eq!(single_value("KEY=''\n"), "");
+        assert_eq!(single_value("KEY=\"\"\n"), "");
+    }
+
+    #[test]
+    fn test_empty_lines() {
+        let input = "\nKEY1=value1\n\n  \n# comment\r\n\nKEY2=value2\n";
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
+        let kinds: Vec<&str> = result
+            .iter()
+            .map(|line| match line {
+                Line::Comment(_) => "comment",
+                Line::Blank => "empty",
+                Line::KeyValue { .. } => "key",
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec!["empty", "key", "empty", "empty", "comment", "empty", "key"]
+        );
+        match &result[4] {
+            Line::Comment(comment) => assert_eq!(comment, " comment"),
+            _ => panic!("Expected Comment, got {:?}", result[4]),
+        }
+    }
+
+    #[test]
+    fn test_export_prefix() {
+        let input = "export KEY=value\n  export\tOTHER=1\nexport=2\nexported=3\n";
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
+        let keys: Vec<(&str, bool)> = result
+            .iter()
+            .map(|line| match line {
+                Line::KeyValue { key, export, .. } => (key.as_str(), *export),
+                _ => panic!("Expected KeyValue, got {:?}", line),
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("KEY", true),
+                ("OTHER", true),
+                ("export", false),
+                ("exported", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_prefix_in_cmd_dialect() {
+        let input = "set KEY=value\n  set\tOTHER=1\nset=2\nsetting=3\nexport E=4\n";
+        let result = parser(Dialect::Cmd, '#').parse(input).unwrap();
+        let keys: Vec<(&str, bool, bool)> = result
+            .iter()
+            .map(|line| match line {
+                Line::KeyValue {
+                    key, set, export, ..
+                } => (key.as_str(), *set, *export),
+                _ => panic!("Expected KeyValue, got {:?}", line),
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("KEY", true, false),
+                ("OTHER", true, false),
+                ("set", false, false),
+                ("setting", false, false),
+                ("E", false, true)
+            ]
+        );
+
+        // Only the cmd dialect knows about `set `
+        assert!(parser(Dialect::Dotenv, '#')
+            .parse("set KEY=value\n")
+            .is_err());
+    }
+
+    #[test]
+    fn test_semicolon_comment_char() {
+        let input = "; whole line\nA=a;b ; trailing\nB=#not a comment\n";
+        let result = parser(Dialect::Dotenv, ';').parse(input).unwrap();
+        assert!(matches!(&result[0], Line::Comment(c) if c == " whole line"));
+        assert!(matches!(
+            &result[1],
+            Line::KeyValue { key, value, comment: Some(c), .. }
+                if key == "A" && value == "a;b" && c == " trailing"
+        ));
+        assert!(matches!(
+            &result[2],
+            Line::KeyValue { key, value, comment: None, .. }
+                if key == "B" && value == "#not a comment"
+        ));
+
+        // `#` stays the default
+        assert!(parser(Dialect::Dotenv, '#').parse("; nope\n").is_err());
+    }
+
+    #[test]
+    fn test_hash_inside_unquoted_value() {
+        let input = "A=a#b\nB=a #b\nC=a\t#b\nD=#b\n";
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
+        let pairs: Vec<(&str, &str, Option<&str>)> = result
+            .iter()
+            .map(|line| match line {
+                Line::KeyValue {
+                    key,
+                    value,
+                    comment,
+                    ..
+                } => (key.as_str(), value.as_str(), comment.as_deref()),
+                _ => panic!("Expected KeyValue, got {:?}", line),
+            })
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("A", "a#b", None),
+                ("B", "a", Some("b")),
+                ("C", "a", Some("b")),
+                ("D", "", Some("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spanned_value() {
+        let input = "KEY = \"a\\nb\" # note\n";
+        let ((line, span), _) = spanned_line_parser(Dialect::Dotenv, '#')
+            .then(end())
+            .parse(input)
+            .unwrap();
+        match line {
+            Line::KeyValue { value, .. } => assert_eq!(value, "a\nb"),
+            _ => panic!("Expected KeyValue, got {:?}", line),
+        }
+        assert_eq!(&input[span.unwrap()], "a\\nb");
+
+        let ((_, span), _) = spanned_line_parser(Dialect::Dotenv, '#')
+            .then(end())
+            .parse("# comment\n")
+            .unwrap();
+        assert_eq!(span, None);
+    }
+
+    #[test]
+    fn test_quoted_value_keeps_surrounding_spaces() {
+        let input = "A=\"  x  \"\nB='  y  '\nC=  z  \n";
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
+        let values: Vec<&str> = result
+            .iter()
+            .map(|line| match line {
+                Line::KeyValue { value, .. } => value.as_str(),
+                _ => panic!("Expected KeyValue, got {:?}", line),
+            })
+            .collect();
+        assert_eq!(values, vec!["  x  ", "  y  ", "z"]);
+    }
+
+    #[test]
+    fn test_equals_sign_in_value() {
+        let input = "DSN=key1=val1;key2=val2\nEMPTY==x\nQUOTED=\"a = b\"\n";
+        let result = parser(Dialect::Dotenv, '#').parse(input).unwrap();
+        let pairs: Vec<(&str, &str)> = result
+            .iter()
+            .map(|line| match line {
+                Line::KeyValue { key, value, .. } => (key.as_str(), value.as_str()),
+                _ => panic!("Expected KeyValue, got {:?}", line),
+            })
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("DSN", "key1=val1;key2=val2"),
+                ("EMPTY", "=x"),
+                ("QUOTED", "a = b"),
+            ]
+        );
+    }
 }
@@ -0,0 +1,213 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use tempfile::tempdir;
+
+/// A command running the envset binary in `dir`, without any `ENVSET_FILE`
+/// from the environment running the tests.
+fn envset(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_envset"));
+    command.current_dir(dir).env_remove("ENVSET_FILE");
+    command
+}
+
+/// Runs `command` with `stdin` piped in, which is where vars to set are read
+/// from when stdin isn't a terminal.
+fn run(command: &mut Command, stdin: &str) -> Output {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_get_fail_on_empty() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join(".env"), "EMPTY=\nQUOTED=\"\"\nFULL=value\n").unwrap();
+
+    for key in ["EMPTY", "QUOTED"] {
+        let output = run(envset(dir.path()).args(["get", "--fail-on-empty", key]), "");
+        assert_eq!(output.status.code(), Some(1), "exit code for {}", key);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "\n");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            format!("Environment variable '{}' is empty\n", key)
+        );
+    }
+
+    let output = run(
+        envset(dir.path()).args(["get", "--fail-on-empty", "FULL"]),
+        "",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "value\n");
+
+    // Without the flag an empty value is found like any other
+    let output = run(envset(dir.path()).args(["get", "EMPTY"]), "");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_exit_changed() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join(".env");
+    fs::write(&file_path, "A=1\n").unwrap();
+
+    // With --dry-run the exit code still reports the change, without making it
+    let output = run(
+        envset(dir.path()).args(["--exit-changed", "--dry-run"]),
+        "B=2\n",
+    );
+    assert_eq!(output.status.code(), Some(10));
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "A=1\n");
+
+    let output = run(envset(dir.path()).arg("--exit-changed"), "A=1\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "A=1\n");
+
+    let output = run(
+        envset(dir.path()).args(["--exit-changed", "--dry-run"]),
+        "A=1\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run(envset(dir.path()).arg("--exit-changed"), "B=2\n");
+    assert_eq!(output.status.code(), Some(10));
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "A=1\nB=2\n");
+
+    // Without the flag a change exits with 0
+    let output = run(&mut envset(dir.path()), "C=3\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_print_path() {
+    let dir = tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    let nested = root.join("app");
+    fs::create_dir(&nested).unwrap();
+
+    let output = run(envset(&root).arg("--print-path"), "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\n", root.join(".env").display())
+    );
+    assert!(!root.join(".env").exists());
+
+    let output = run(
+        envset(&root)
+            .env("ENVSET_FILE", "custom.env")
+            .arg("--print-path"),
+        "",
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\n", root.join("custom.env").display())
+    );
+    assert!(!root.join("custom.env").exists());
+
+    // The file found in a parent directory is printed, and none is created in
+    // the current one
+    fs::write(root.join(".env"), "A=1\n").unwrap();
+    let output = run(
+        envset(&nested).args(["--search-parents", "--print-path"]),
+        "",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\n", root.join(".env").display())
+    );
+    assert!(!nested.join(".env").exists());
+    assert_eq!(fs::read_to_string(root.join(".env")).unwrap(), "A=1\n");
+}
+
+#[test]
+fn test_exit_codes() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join(".env");
+    fs::write(&file_path, "A=1\n").unwrap();
+
+    // 1 when a key isn't found
+    let output = run(envset(dir.path()).args(["get", "MISSING"]), "");
+    assert_eq!(output.status.code(), Some(1));
+
+    // 2 for usage errors, whether clap or envset rejects the arguments
+    let output = run(envset(dir.path()).arg("--no-such-flag"), "");
+    assert_eq!(output.status.code(), Some(2));
+    let output = run(
+        envset(dir.path()).args(["print", "--json", "--only", "A"]),
+        "",
+    );
+    assert_eq!(output.status.code(), Some(2));
+
+    // 2 for files that can't be read
+    let output = run(
+        envset(dir.path()).args(["--file", "missing.env", "print"]),
+        "",
+    );
+    assert_eq!(output.status.code(), Some(2));
+    assert!(!dir.path().join("missing.env").exists());
+
+    // 2 for vars or files that don't parse
+    let output = run(&mut envset(dir.path()), "1A=2\n");
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "A=1\n");
+    fs::write(&file_path, "A=1\n1B=2\n").unwrap();
+    let output = run(envset(dir.path()).args(["get", "A"]), "");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_envset_file_precedence() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join(".env"), "WHICH=default\n").unwrap();
+    fs::write(dir.path().join(".env.development"), "WHICH=development\n").unwrap();
+    fs::write(dir.path().join(".env.test"), "WHICH=test\n").unwrap();
+
+    let output = run(envset(dir.path()).args(["get", "WHICH"]), "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "default\n");
+
+    let output = run(
+        envset(dir.path())
+            .env("ENVSET_FILE", ".env.development")
+            .args(["get", "WHICH"]),
+        "",
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "development\n");
+
+    // An explicit --file wins over ENVSET_FILE, for writes too
+    let output = run(
+        envset(dir.path())
+            .env("ENVSET_FILE", ".env.development")
+            .args(["--file", ".env.test", "get", "WHICH"]),
+        "",
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "test\n");
+    let output = run(
+        envset(dir.path())
+            .env("ENVSET_FILE", ".env.development")
+            .args(["--file", ".env.test"]),
+        "ADDED=1\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        fs::read_to_string(dir.path().join(".env.test")).unwrap(),
+        "WHICH=test\nADDED=1\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join(".env.development")).unwrap(),
+        "WHICH=development\n"
+    );
+}